@@ -0,0 +1,278 @@
+//! Arithmetic, bitwise, concatenation, length and comparison operators of Lua 5.4.
+
+use std::cmp::Ordering;
+
+use anyhow::bail;
+
+use crate::lex::str2number;
+use crate::str::LossyStr;
+use crate::{TokenKind, Value};
+
+pub fn add(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    arith(a, b, i64::wrapping_add, |a, b| a + b)
+}
+
+pub fn sub(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    arith(a, b, i64::wrapping_sub, |a, b| a - b)
+}
+
+pub fn mul(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    arith(a, b, i64::wrapping_mul, |a, b| a * b)
+}
+
+/// `/` always produces a float.
+pub fn div(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    let (a, b) = (to_number(a)?.as_float(), to_number(b)?.as_float());
+    Ok(Value::Float(a / b))
+}
+
+/// `^` always produces a float.
+pub fn pow(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    let (a, b) = (to_number(a)?.as_float(), to_number(b)?.as_float());
+    Ok(Value::Float(a.powf(b)))
+}
+
+/// Floor division, rounding the quotient towards minus infinity.
+pub fn idiv(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    match (to_number(a)?, to_number(b)?) {
+        (Number::Integer(_), Number::Integer(0)) => bail!("attempt to perform 'n//0'"),
+        (Number::Integer(a), Number::Integer(b)) => {
+            // `i64::MIN // -1` 溢出时回绕
+            let q = a.wrapping_div(b);
+            let q = if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+                q - 1
+            } else {
+                q
+            };
+            Ok(Value::Integer(q))
+        }
+        (a, b) => Ok(Value::Float((a.as_float() / b.as_float()).floor())),
+    }
+}
+
+/// Modulo whose result has the same sign as the divisor.
+pub fn modulo(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    match (to_number(a)?, to_number(b)?) {
+        (Number::Integer(_), Number::Integer(0)) => bail!("attempt to perform 'n%0'"),
+        (Number::Integer(a), Number::Integer(b)) => {
+            let r = a.wrapping_rem(b);
+            let r = if r != 0 && (r ^ b) < 0 { r + b } else { r };
+            Ok(Value::Integer(r))
+        }
+        (a, b) => {
+            let (a, b) = (a.as_float(), b.as_float());
+            let mut r = a % b;
+            if (r > 0.0 && b < 0.0) || (r < 0.0 && b > 0.0) {
+                r += b;
+            }
+            Ok(Value::Float(r))
+        }
+    }
+}
+
+pub fn unm(a: &Value) -> anyhow::Result<Value> {
+    match to_number(a)? {
+        Number::Integer(i) => Ok(Value::Integer(i.wrapping_neg())),
+        Number::Float(f) => Ok(Value::Float(-f)),
+    }
+}
+
+pub fn bit_and(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    Ok(Value::Integer(to_integer(a)? & to_integer(b)?))
+}
+
+pub fn bit_or(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    Ok(Value::Integer(to_integer(a)? | to_integer(b)?))
+}
+
+pub fn bit_xor(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    Ok(Value::Integer(to_integer(a)? ^ to_integer(b)?))
+}
+
+pub fn bit_not(a: &Value) -> anyhow::Result<Value> {
+    Ok(Value::Integer(!to_integer(a)?))
+}
+
+pub fn shift_left(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    Ok(Value::Integer(shift(to_integer(a)?, to_integer(b)?)))
+}
+
+pub fn shift_right(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    Ok(Value::Integer(shift(
+        to_integer(a)?,
+        to_integer(b)?.wrapping_neg(),
+    )))
+}
+
+/// Concatenate two strings or numbers into a string.
+pub fn concat(a: &Value, b: &Value) -> anyhow::Result<Value> {
+    let mut s = to_bytes(a)?;
+    s.extend_from_slice(&to_bytes(b)?);
+    Ok(Value::String(LossyStr::from(s.as_slice())))
+}
+
+/// The length of a string is its number of bytes.
+pub fn len(a: &Value) -> anyhow::Result<Value> {
+    match a {
+        Value::String(s) => Ok(Value::Integer(s.as_bytes().len() as i64)),
+        Value::Table(t) => Ok(Value::Integer(t.borrow().len() as i64)),
+        v => bail!("attempt to get length of a {} value", v.type_name()),
+    }
+}
+
+/// Raw equality, where an integer equals a float of the same mathematical value.
+pub fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Integer(i), Value::Float(f)) | (Value::Float(f), Value::Integer(i)) => {
+            cmp_int_float(*i, *f) == Some(Ordering::Equal)
+        }
+        (a, b) => a == b,
+    }
+}
+
+pub fn less(a: &Value, b: &Value) -> anyhow::Result<bool> {
+    Ok(compare(a, b)? == Some(Ordering::Less))
+}
+
+pub fn less_eq(a: &Value, b: &Value) -> anyhow::Result<bool> {
+    Ok(matches!(
+        compare(a, b)?,
+        Some(Ordering::Less | Ordering::Equal)
+    ))
+}
+
+/// Order two numbers or two strings, where strings are compared byte by byte.
+/// Nothing is ordered with NaN.
+fn compare(a: &Value, b: &Value) -> anyhow::Result<Option<Ordering>> {
+    match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => Ok(Some(a.cmp(b))),
+        (Value::Float(a), Value::Float(b)) => Ok(a.partial_cmp(b)),
+        (Value::Integer(i), Value::Float(f)) => Ok(cmp_int_float(*i, *f)),
+        (Value::Float(f), Value::Integer(i)) => Ok(cmp_int_float(*i, *f).map(Ordering::reverse)),
+        (Value::String(a), Value::String(b)) => Ok(Some(a.as_bytes().cmp(b.as_bytes()))),
+        (a, b) if a.type_name() == b.type_name() => {
+            bail!("attempt to compare two {} values", a.type_name())
+        }
+        (a, b) => bail!(
+            "attempt to compare {} with {}",
+            a.type_name(),
+            b.type_name()
+        ),
+    }
+}
+
+/// Compare an integer with a float exactly,
+/// without converting the integer to a float which may lose precision.
+fn cmp_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= 2f64.powi(63) {
+        Some(Ordering::Less)
+    } else if f < -(2f64.powi(63)) {
+        Some(Ordering::Greater)
+    } else {
+        // 此时 f 的整数部分可以用 i128 精确表示
+        let floor = f.floor();
+        match (i as i128).cmp(&(floor as i128)) {
+            Ordering::Equal if f != floor => Some(Ordering::Less),
+            ord => Some(ord),
+        }
+    }
+}
+
+/// Convert a number or a numeric string to a float.
+pub fn to_float(v: &Value) -> Option<f64> {
+    to_number(v).ok().map(Number::as_float)
+}
+
+/// Convert a number or a numeric string to an integer,
+/// if it has an exact integer representation.
+pub fn to_int(v: &Value) -> Option<i64> {
+    to_integer(v).ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn as_float(self) -> f64 {
+        match self {
+            Self::Integer(i) => i as f64,
+            Self::Float(f) => f,
+        }
+    }
+}
+
+/// Integer operands produce an integer, otherwise both are converted to floats.
+fn arith(
+    a: &Value,
+    b: &Value,
+    int_op: fn(i64, i64) -> i64,
+    float_op: fn(f64, f64) -> f64,
+) -> anyhow::Result<Value> {
+    match (to_number(a)?, to_number(b)?) {
+        (Number::Integer(a), Number::Integer(b)) => Ok(Value::Integer(int_op(a, b))),
+        (a, b) => Ok(Value::Float(float_op(a.as_float(), b.as_float()))),
+    }
+}
+
+/// Convert an operand of arithmetic to a number, where strings are coerced.
+fn to_number(v: &Value) -> anyhow::Result<Number> {
+    let number = match v {
+        Value::Integer(i) => Some(Number::Integer(*i)),
+        Value::Float(f) => Some(Number::Float(*f)),
+        Value::String(s) => match str2number(s.as_bytes()) {
+            Some(TokenKind::Integer(i)) => Some(Number::Integer(i)),
+            Some(TokenKind::Float(f)) => Some(Number::Float(f)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match number {
+        Some(number) => Ok(number),
+        None => bail!("attempt to perform arithmetic on a {} value", v.type_name()),
+    }
+}
+
+/// Convert an operand of bitwise operations to an integer,
+/// where floats must have an exact integer representation.
+fn to_integer(v: &Value) -> anyhow::Result<i64> {
+    let Ok(number) = to_number(v) else {
+        bail!(
+            "attempt to perform bitwise operation on a {} value",
+            v.type_name()
+        );
+    };
+
+    match number {
+        Number::Integer(i) => Ok(i),
+        // 2^63 恰好可以用浮点数精确表示
+        Number::Float(f) if f.fract() == 0.0 && (-(2f64.powi(63))..2f64.powi(63)).contains(&f) => {
+            Ok(f as i64)
+        }
+        Number::Float(_) => bail!("number has no integer representation"),
+    }
+}
+
+/// Shift `x` logically to the left by `n` bits, or to the right if `n` is negative.
+fn shift(x: i64, n: i64) -> i64 {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((x as u64) << n) as i64
+    } else {
+        ((x as u64) >> -n) as i64
+    }
+}
+
+fn to_bytes(v: &Value) -> anyhow::Result<Vec<u8>> {
+    match v {
+        Value::String(s) => Ok(s.as_bytes().to_vec()),
+        Value::Integer(_) | Value::Float(_) => Ok(format!("{v:?}").into_bytes()),
+        v => bail!("attempt to concatenate a {} value", v.type_name()),
+    }
+}
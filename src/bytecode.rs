@@ -1,15 +1,73 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum ByteCode {
-    GetGlobal(u8, u8),       // A  Bx   R[A] := G[K[Bx]]
-    Move(u8, u8),            // A  B    R[A] := R[B]
-    LoadConst(u8, u8),       // A  Bx   R[A] := K[Bx]
-    LoadNil(u8),             // A  B    R[A], R[A+1], ..., R[A+B] := nil
-    LoadBool(u8, bool),      // A  B    R[A] := B
-    LoadInt(u8, i16),        // A  B    R[A] := B
-    Call(u8, u8),            // A  B    R[A] := R[A](R[A+1], ... ,R[A+B-1])
-    SetGlobalConst(u8, u8),  // Ax Bx   G[K[Ax]] := K[Bx]
-    SetGlobalLocal(u8, u8),  // Ax B    G[K[Ax]] := R[B]
-    SetGlobalGlobal(u8, u8), // Ax Bx   G[K[Ax]] := G[K[Bx]]
+    GetGlobal(u8, u16),        // A  Bx   R[A] := G[K[Bx]]
+    Move(u8, u8),              // A  B    R[A] := R[B]
+    LoadConst(u8, u16),        // A  Bx   R[A] := K[Bx]
+    LoadNil(u8),               // A  B    R[A], R[A+1], ..., R[A+B] := nil
+    LoadBool(u8, bool),        // A  B    R[A] := B
+    LoadInt(u8, i16),          // A  B    R[A] := B
+    Call(u8, u8, u8),          // A  B C  R[A], ... ,R[A+C-2] := R[A](R[A+1], ... ,R[A+B-1])
+    Return(u8, u8),            // A  B    return R[A], ... ,R[A+B-2]
+    VarArgs(u8, u8),           // A  C    R[A], ... ,R[A+C-2] := vararg
+    SetGlobalConst(u16, u16),  // Ax Bx   G[K[Ax]] := K[Bx]
+    SetGlobalLocal(u16, u8),   // Ax B    G[K[Ax]] := R[B]
+    SetGlobalGlobal(u16, u16), // Ax Bx   G[K[Ax]] := G[K[Bx]]
+
+    // 表
+    NewTable(u8, u8, u8), // A B C   R[A] := {} with B array items and C hash items
+    GetTable(u8, u8, u8), // A B C   R[A] := R[B][R[C]]
+    GetField(u8, u8, u8), // A B C   R[A] := R[B][K[C]]
+    GetI(u8, u8, u8),     // A B C   R[A] := R[B][C]
+    SetTable(u8, u8, u8), // A B C   R[A][R[B]] := R[C]
+    SetField(u8, u8, u8), // A B C   R[A][K[B]] := R[C]
+    SetI(u8, u8, u8),     // A B C   R[A][B] := R[C]
+    SetList(u8, u8, u16), // A B C   R[A][C+i] := R[A+i], 1 <= i <= B
+
+    // 闭包
+    GetUpval(u8, u8), // A B     R[A] := UpValue[B]
+    SetUpval(u8, u8), // A B     UpValue[A] := R[B]
+    Closure(u8, u16), // A Bx    R[A] := closure(KPROTO[Bx])
+    Close(u8),        // A       close all upvalues >= R[A]
+
+    // 跳转，偏移相对于下一条字节码
+    Jump(i16),            // sJ      pc += sJ
+    Test(u8, i16),        // A sJ    if not R[A] then pc += sJ
+    TestAndJump(u8, i16), // A sJ    if not R[A] then pc += sJ
+    TestOrJump(u8, i16),  // A sJ    if R[A] then pc += sJ
+
+    // 数值 for 循环
+    ForPrep(u8, u16), // A Bx    <check values and prepare counters>; if not to run then pc += Bx
+    ForLoop(u8, u16), // A Bx    update counters; if loop continues then pc -= Bx
+
+    // 泛型 for 循环
+    TForPrep(u8, u16), // A Bx    check the to-be-closed R[A+3]; pc += Bx
+    TForCall(u8, u8),  // A C     R[A+4], ... ,R[A+3+C] := R[A](R[A+1], R[A+2])
+    TForLoop(u8, u16), // A Bx    if R[A+4] ~= nil then { R[A+2] := R[A+4]; pc -= Bx }
+
+    // 运算
+    Add(u8, u8, u8),    // A B C   R[A] := R[B] + R[C]
+    Sub(u8, u8, u8),    // A B C   R[A] := R[B] - R[C]
+    Mul(u8, u8, u8),    // A B C   R[A] := R[B] * R[C]
+    Div(u8, u8, u8),    // A B C   R[A] := R[B] / R[C]
+    Idiv(u8, u8, u8),   // A B C   R[A] := R[B] // R[C]
+    Mod(u8, u8, u8),    // A B C   R[A] := R[B] % R[C]
+    Pow(u8, u8, u8),    // A B C   R[A] := R[B] ^ R[C]
+    BitAnd(u8, u8, u8), // A B C   R[A] := R[B] & R[C]
+    BitOr(u8, u8, u8),  // A B C   R[A] := R[B] | R[C]
+    BitXor(u8, u8, u8), // A B C   R[A] := R[B] ~ R[C]
+    ShiftL(u8, u8, u8), // A B C   R[A] := R[B] << R[C]
+    ShiftR(u8, u8, u8), // A B C   R[A] := R[B] >> R[C]
+    Concat(u8, u8, u8), // A B C   R[A] := R[B] .. R[C]
+    Unm(u8, u8),        // A B     R[A] := -R[B]
+    BitNot(u8, u8),     // A B     R[A] := ~R[B]
+    Len(u8, u8),        // A B     R[A] := #R[B]
+    Not(u8, u8),        // A B     R[A] := not R[B]
+
+    // 比较
+    Equal(u8, u8, u8), // A B C   R[A] := R[B] == R[C]
+    NotEq(u8, u8, u8), // A B C   R[A] := R[B] ~= R[C]
+    Less(u8, u8, u8),  // A B C   R[A] := R[B] < R[C]
+    LesEq(u8, u8, u8), // A B C   R[A] := R[B] <= R[C]
 }
 
 pub struct ByteCodeStack<'a>(pub &'a [ByteCode]);
@@ -0,0 +1,130 @@
+//! Recovering variable names from bytecodes for error messages, the way Lua 5.4 does.
+
+use std::ops::Range;
+
+use crate::{ByteCode, FuncProto, Value};
+
+/// Describe the variable whose value is in register `reg` when executing the bytecode
+/// at `pc`, e.g. `global 'cfg'`, or `None` if it is a temporary value.
+pub fn var_info(proto: &FuncProto, pc: usize, reg: usize) -> Option<String> {
+    if let Some(var) = proto
+        .locals
+        .iter()
+        .find(|var| var.reg == reg && (var.start_pc..var.end_pc).contains(&pc))
+    {
+        return Some(format!("local '{}'", var.name));
+    }
+
+    // 临时寄存器的值来自最后一条写入它的字节码
+    let setter = find_setter(&proto.bytecodes[..pc], reg)?;
+    match proto.bytecodes[setter] {
+        ByteCode::GetGlobal(_, name) => Some(format!(
+            "global '{}'",
+            proto.constants[name as usize].as_identifier()?
+        )),
+        ByteCode::GetUpval(_, idx) => {
+            Some(format!("upvalue '{}'", proto.upvalue_names[idx as usize]))
+        }
+        ByteCode::GetField(_, _, key) => match &proto.constants[key as usize] {
+            Value::String(s) => Some(format!("field '{s}'")),
+            _ => None,
+        },
+        // 从更低的寄存器复制来的值，可能是局部变量
+        ByteCode::Move(_, src) if (src as usize) < reg => var_info(proto, setter, src as usize),
+        _ => None,
+    }
+}
+
+/// Find the last bytecode in `codes` which writes register `reg`,
+/// unless it may be skipped by a jump.
+fn find_setter(codes: &[ByteCode], reg: usize) -> Option<usize> {
+    let mut setter = None;
+    // 跳转目标之前的写入不一定被执行
+    let mut jump_target = 0;
+    for (pc, &code) in codes.iter().enumerate() {
+        if written_regs(code).contains(&reg) {
+            setter = (pc >= jump_target).then_some(pc);
+        }
+        if let Some(target) = jump_target_of(pc, code) {
+            if target <= codes.len() {
+                jump_target = jump_target.max(target);
+            }
+        }
+    }
+    setter
+}
+
+/// The registers written by `code`.
+fn written_regs(code: ByteCode) -> Range<usize> {
+    match code {
+        ByteCode::GetGlobal(dst, _)
+        | ByteCode::Move(dst, _)
+        | ByteCode::LoadConst(dst, _)
+        | ByteCode::LoadNil(dst)
+        | ByteCode::LoadBool(dst, _)
+        | ByteCode::LoadInt(dst, _)
+        | ByteCode::NewTable(dst, _, _)
+        | ByteCode::GetTable(dst, _, _)
+        | ByteCode::GetField(dst, _, _)
+        | ByteCode::GetI(dst, _, _)
+        | ByteCode::GetUpval(dst, _)
+        | ByteCode::Closure(dst, _)
+        | ByteCode::Add(dst, _, _)
+        | ByteCode::Sub(dst, _, _)
+        | ByteCode::Mul(dst, _, _)
+        | ByteCode::Div(dst, _, _)
+        | ByteCode::Idiv(dst, _, _)
+        | ByteCode::Mod(dst, _, _)
+        | ByteCode::Pow(dst, _, _)
+        | ByteCode::BitAnd(dst, _, _)
+        | ByteCode::BitOr(dst, _, _)
+        | ByteCode::BitXor(dst, _, _)
+        | ByteCode::ShiftL(dst, _, _)
+        | ByteCode::ShiftR(dst, _, _)
+        | ByteCode::Concat(dst, _, _)
+        | ByteCode::Unm(dst, _)
+        | ByteCode::BitNot(dst, _)
+        | ByteCode::Len(dst, _)
+        | ByteCode::Not(dst, _)
+        | ByteCode::Equal(dst, _, _)
+        | ByteCode::NotEq(dst, _, _)
+        | ByteCode::Less(dst, _, _)
+        | ByteCode::LesEq(dst, _, _) => dst as usize..dst as usize + 1,
+        // 返回值的个数不定，视为写入之上的所有寄存器
+        ByteCode::Call(dst, _, _) | ByteCode::VarArgs(dst, _) => dst as usize..usize::MAX,
+        ByteCode::TForCall(base, _) => base as usize + 4..usize::MAX,
+        ByteCode::ForPrep(base, _) | ByteCode::ForLoop(base, _) => base as usize..base as usize + 4,
+        ByteCode::TForLoop(base, _) => base as usize + 2..base as usize + 3,
+        ByteCode::Return(..)
+        | ByteCode::SetGlobalConst(..)
+        | ByteCode::SetGlobalLocal(..)
+        | ByteCode::SetGlobalGlobal(..)
+        | ByteCode::SetTable(..)
+        | ByteCode::SetField(..)
+        | ByteCode::SetI(..)
+        | ByteCode::SetList(..)
+        | ByteCode::SetUpval(..)
+        | ByteCode::Close(_)
+        | ByteCode::Jump(_)
+        | ByteCode::Test(..)
+        | ByteCode::TestAndJump(..)
+        | ByteCode::TestOrJump(..)
+        | ByteCode::TForPrep(..) => 0..0,
+    }
+}
+
+/// The target of `code` at `pc` if it jumps forward.
+fn jump_target_of(pc: usize, code: ByteCode) -> Option<usize> {
+    match code {
+        ByteCode::Jump(jmp)
+        | ByteCode::Test(_, jmp)
+        | ByteCode::TestAndJump(_, jmp)
+        | ByteCode::TestOrJump(_, jmp)
+            if jmp > 0 =>
+        {
+            Some(pc + 1 + jmp as usize)
+        }
+        ByteCode::ForPrep(_, jmp) | ByteCode::TForPrep(_, jmp) => Some(pc + 1 + jmp as usize),
+        _ => None,
+    }
+}
@@ -0,0 +1,79 @@
+use nom::error::{FromExternalError, ParseError};
+
+use super::{LexResult, Location};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LexError {
+    #[error("{0}: unfinished string")]
+    UnfinishedString(Location),
+    #[error("{0}: unfinished long string")]
+    UnfinishedLongString(Location),
+    #[error("{0}: unfinished long comment")]
+    UnfinishedLongComment(Location),
+    #[error("{0}: malformed number")]
+    MalformedNumber(Location),
+    #[error("{location}: {reason}")]
+    InvalidEscape {
+        location: Location,
+        reason: &'static str,
+    },
+    #[error("{location}: unexpected symbol near '{}'", .actual.escape_ascii())]
+    UnexpectedChar { location: Location, actual: u8 },
+}
+
+/// The cause of a lexing failure, before it is located in the chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A plain nom error, which makes the alternatives backtrack
+    Nom,
+    UnfinishedString,
+    UnfinishedLongString,
+    UnfinishedLongComment,
+    MalformedNumber,
+    InvalidEscape(&'static str),
+}
+
+#[derive(Debug)]
+pub struct Error<'a> {
+    pub input: &'a [u8],
+    pub kind: ErrorKind,
+}
+
+impl<'a> ParseError<&'a [u8]> for Error<'a> {
+    fn from_error_kind(input: &'a [u8], _: nom::error::ErrorKind) -> Self {
+        Self {
+            input,
+            kind: ErrorKind::Nom,
+        }
+    }
+
+    fn append(_: &'a [u8], _: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<'a, E> FromExternalError<&'a [u8], E> for Error<'a> {
+    fn from_external_error(input: &'a [u8], kind: nom::error::ErrorKind, _: E) -> Self {
+        Self::from_error_kind(input, kind)
+    }
+}
+
+/// Fail unrecoverably at `input` because of `kind`.
+pub fn fail<T>(input: &[u8], kind: ErrorKind) -> LexResult<'_, T> {
+    Err(nom::Err::Failure(Error { input, kind }))
+}
+
+/// Give a cause to the unrecoverable failure of `f` which has none,
+/// locating the failure at the input of `f`.
+pub fn cause<'a, O>(
+    kind: ErrorKind,
+    mut f: impl FnMut(&'a [u8]) -> LexResult<'a, O>,
+) -> impl FnMut(&'a [u8]) -> LexResult<'a, O> {
+    move |input| match f(input) {
+        Err(nom::Err::Failure(Error {
+            kind: ErrorKind::Nom,
+            ..
+        })) => fail(input, kind),
+        res => res,
+    }
+}
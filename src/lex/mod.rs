@@ -1,13 +1,16 @@
+mod error;
+mod number;
 mod string;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::str;
 
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag, take_while1},
-    character::complete::{char, digit1, multispace0, one_of},
-    combinator::{eof, map_res, opt, recognize, value},
-    sequence::{delimited, preceded, tuple},
+    character::complete::{char, one_of},
+    combinator::{eof, opt, recognize, value},
+    sequence::preceded,
     IResult,
 };
 use once_cell::sync::Lazy;
@@ -16,76 +19,140 @@ use tinyvec::TinyVec;
 
 use crate::str::LossyStr;
 
-use self::string::lex_string;
+pub use self::error::LexError;
+pub(crate) use self::number::str2number;
 
-static UNIT_TOKEN: Lazy<HashMap<&'static str, Token>> = Lazy::new(|| {
+use self::error::{cause, Error, ErrorKind};
+use self::number::lex_number;
+use self::string::{lex_string, long_bracket};
+
+static UNIT_TOKEN: Lazy<HashMap<&'static str, TokenKind>> = Lazy::new(|| {
     HashMap::from_iter([
-        ("true", Token::True),
-        ("false", Token::False),
-        ("nil", Token::Nil),
-        ("and", Token::And),
-        ("break", Token::Break),
-        ("do", Token::Do),
-        ("else", Token::Else),
-        ("elseif", Token::Elseif),
-        ("end", Token::End),
-        ("for", Token::For),
-        ("function", Token::Function),
-        ("goto", Token::Goto),
-        ("if", Token::If),
-        ("in", Token::In),
-        ("local", Token::Local),
-        ("not", Token::Not),
-        ("or", Token::Or),
-        ("return", Token::Return),
-        ("then", Token::Then),
-        ("while", Token::While),
-        ("repeat", Token::Repeat),
-        ("until", Token::Until),
-        ("<<", Token::ShiftL),
-        (">>", Token::ShiftR),
-        ("//", Token::Idiv),
-        ("==", Token::Equal),
-        ("~=", Token::NotEq),
-        ("<=", Token::LesEq),
-        (">=", Token::GreEq),
-        ("::", Token::DoubColon),
-        ("..", Token::Concat),
-        ("...", Token::Dots),
-        ("+", Token::Add),
-        ("-", Token::Sub),
-        ("*", Token::Mul),
-        ("/", Token::Div),
-        ("%", Token::Mod),
-        ("^", Token::Pow),
-        ("#", Token::Len),
-        ("&", Token::BitAnd),
-        ("~", Token::BitXor),
-        ("|", Token::BitOr),
-        ("<", Token::Less),
-        (">", Token::Greater),
-        ("=", Token::Assign),
-        ("(", Token::ParL),
-        (")", Token::ParR),
-        ("{", Token::CurlyL),
-        ("}", Token::CurlyR),
-        ("[", Token::SqurL),
-        ("]", Token::SqurR),
-        (";", Token::SemiColon),
-        (":", Token::Colon),
-        (",", Token::Comma),
-        (".", Token::Dot),
+        ("true", TokenKind::True),
+        ("false", TokenKind::False),
+        ("nil", TokenKind::Nil),
+        ("and", TokenKind::And),
+        ("break", TokenKind::Break),
+        ("do", TokenKind::Do),
+        ("else", TokenKind::Else),
+        ("elseif", TokenKind::Elseif),
+        ("end", TokenKind::End),
+        ("for", TokenKind::For),
+        ("function", TokenKind::Function),
+        ("goto", TokenKind::Goto),
+        ("if", TokenKind::If),
+        ("in", TokenKind::In),
+        ("local", TokenKind::Local),
+        ("not", TokenKind::Not),
+        ("or", TokenKind::Or),
+        ("return", TokenKind::Return),
+        ("then", TokenKind::Then),
+        ("while", TokenKind::While),
+        ("repeat", TokenKind::Repeat),
+        ("until", TokenKind::Until),
+        ("<<", TokenKind::ShiftL),
+        (">>", TokenKind::ShiftR),
+        ("//", TokenKind::Idiv),
+        ("==", TokenKind::Equal),
+        ("~=", TokenKind::NotEq),
+        ("<=", TokenKind::LesEq),
+        (">=", TokenKind::GreEq),
+        ("::", TokenKind::DoubColon),
+        ("..", TokenKind::Concat),
+        ("...", TokenKind::Dots),
+        ("+", TokenKind::Add),
+        ("-", TokenKind::Sub),
+        ("*", TokenKind::Mul),
+        ("/", TokenKind::Div),
+        ("%", TokenKind::Mod),
+        ("^", TokenKind::Pow),
+        ("#", TokenKind::Len),
+        ("&", TokenKind::BitAnd),
+        ("~", TokenKind::BitXor),
+        ("|", TokenKind::BitOr),
+        ("<", TokenKind::Less),
+        (">", TokenKind::Greater),
+        ("=", TokenKind::Assign),
+        ("(", TokenKind::ParL),
+        (")", TokenKind::ParR),
+        ("{", TokenKind::CurlyL),
+        ("}", TokenKind::CurlyR),
+        ("[", TokenKind::SqurL),
+        ("]", TokenKind::SqurR),
+        (";", TokenKind::SemiColon),
+        (":", TokenKind::Colon),
+        (",", TokenKind::Comma),
+        (".", TokenKind::Dot),
     ])
 });
 
 #[derive(Debug)]
 pub struct Lexer<'a> {
-    source: &'a str,
+    chunk: SmolStr,
+    source: &'a [u8],
+    /// 当前位置的字节偏移
+    pos: usize,
+    line: usize,
+    /// 当前行首的字节偏移
+    line_start: usize,
+    /// 是否保留注释与空白
+    lossless: bool,
+    /// 预读的 token
+    ahead: VecDeque<Token>,
+    /// 作为迭代器时，是否已经结束
+    done: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    /// Comments and whitespace preceding the token, only kept by a lossless lexer
+    pub trivia: Vec<Trivia>,
+}
+
+/// Source text that doesn't affect the meaning of a chunk.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TriviaKind {
+    Whitespace,
+    /// `-- ...`
+    LineComment,
+    /// `--[[ ... ]]`
+    LongComment,
+    /// `#...` in the first line
+    Shebang,
+}
+
+/// Location of a token in the source.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    /// Byte offset of the first byte
+    pub start: usize,
+    /// Byte offset past the last byte
+    pub end: usize,
+    /// 1-based line number of the first byte
+    pub line: usize,
+    /// 1-based column (in bytes) of the first byte
+    pub col: usize,
+}
+
+/// Position in a chunk, displayed as `chunk:line:col`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Location {
+    pub chunk: SmolStr,
+    pub line: usize,
+    pub col: usize,
 }
 
 #[rustfmt::skip]
 #[derive(Debug, PartialEq, Clone)]
-pub enum Token {
+pub enum TokenKind {
     // keywords
     And,    Break,  Do,     Else,   Elseif, End,
     False,  For,    Function, Goto, If,     In,
@@ -113,86 +180,255 @@ pub enum Token {
 
     // end
     Eof,
-
-    // comment
-    Comment
 }
 
-pub type LexError = nom::Err<nom::error::Error<String>>;
+type LexResult<'a, T> = IResult<&'a [u8], T, Error<'a>>;
 
 impl<'a> Lexer<'a> {
-    pub fn new(s: &'a str) -> Self {
-        Self { source: s }
+    pub fn new(chunk: &str, source: &'a [u8]) -> Self {
+        Self {
+            chunk: SmolStr::new(chunk),
+            source,
+            pos: 0,
+            line: 1,
+            line_start: 0,
+            lossless: false,
+            ahead: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Create a lexer which keeps comments and whitespace as trivia of tokens,
+    /// so that the source can be rebuilt from the tokens.
+    pub fn lossless(chunk: &str, source: &'a [u8]) -> Self {
+        Self {
+            lossless: true,
+            ..Self::new(chunk, source)
+        }
+    }
+
+    /// Consume the next token, which is `Eof` at and past the end.
+    pub fn next_token(&mut self) -> Result<Token, LexError> {
+        match self.ahead.pop_front() {
+            Some(token) => Ok(token),
+            None => self.lex_token(),
+        }
     }
 
-    pub fn next(&mut self) -> Result<Token, LexError> {
-        lex(self.source)
-            .map(|(input, output)| {
-                self.source = input;
-                output
-            })
-            .map_err(|e| e.to_owned())
+    /// Look at the next token without consuming it.
+    pub fn peek(&mut self) -> Result<&Token, LexError> {
+        self.peek_nth(0)
+    }
+
+    /// Look at the `n`-th (0-based) token after the current position without consuming it.
+    /// Peeking past the end gives `Eof`.
+    pub fn peek_nth(&mut self, n: usize) -> Result<&Token, LexError> {
+        while self.ahead.len() <= n {
+            let token = self.lex_token()?;
+            self.ahead.push_back(token);
+        }
+        Ok(&self.ahead[n])
+    }
+
+    /// Source text of `span`.
+    pub fn text(&self, span: &Span) -> &'a [u8] {
+        &self.source[span.start..span.end]
+    }
+
+    pub fn location(&self, span: &Span) -> Location {
+        Location {
+            chunk: self.chunk.clone(),
+            line: span.line,
+            col: span.col,
+        }
     }
 }
 
-fn lex(input: &str) -> IResult<&str, Token> {
-    preceded(
-        multispace0,
-        alt((
-            lex_string,
-            lex_comment,
-            lex_float,
-            lex_integer,
-            lex_word,
-            lex_chars,
-            value(Token::Eof, eof),
-        )),
-    )(input)
+impl Lexer<'_> {
+    fn lex_token(&mut self) -> Result<Token, LexError> {
+        let mut trivia = Vec::new();
+        // 跳过首行的 `#!/usr/bin/env rua`
+        if self.pos == 0 {
+            if let Ok((input, _)) = shebang(self.source) {
+                let start = self.span_start();
+                self.advance_to(input);
+                if self.lossless {
+                    trivia.push(Trivia {
+                        kind: TriviaKind::Shebang,
+                        span: self.span_end(start),
+                    });
+                }
+            }
+        }
+
+        loop {
+            let start = self.span_start();
+            let input = &self.source[self.pos..];
+            let (input, kind) = match lex_trivia(input) {
+                Ok(output) => output,
+                Err(nom::Err::Error(_)) => break,
+                Err(e) => return Err(self.lex_error(e)),
+            };
+            self.advance_to(input);
+            if self.lossless {
+                trivia.push(Trivia {
+                    kind,
+                    span: self.span_end(start),
+                });
+            }
+        }
+
+        let start = self.span_start();
+        let (input, kind) = lex(&self.source[self.pos..]).map_err(|e| self.lex_error(e))?;
+        self.advance_to(input);
+
+        Ok(Token {
+            kind,
+            span: self.span_end(start),
+            trivia,
+        })
+    }
+
+    /// Span starting at the current position, whose end is not yet known.
+    fn span_start(&self) -> Span {
+        Span {
+            start: self.pos,
+            end: self.pos,
+            line: self.line,
+            col: self.pos - self.line_start + 1,
+        }
+    }
+
+    fn span_end(&self, start: Span) -> Span {
+        Span {
+            end: self.pos,
+            ..start
+        }
+    }
+
+    /// Move forward to `rest`, the remaining input, counting the lines passed.
+    fn advance_to(&mut self, rest: &[u8]) {
+        let end = self.source.len() - rest.len();
+        let (lines, line_start) = line_breaks(&self.source[self.pos..end]);
+        self.line += lines;
+        if let Some(line_start) = line_start {
+            self.line_start = self.pos + line_start;
+        }
+        self.pos = end;
+    }
+
+    fn lex_error(&self, e: nom::Err<Error>) -> LexError {
+        match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => self.error(e),
+            nom::Err::Incomplete(_) => unreachable!("lexers are complete"),
+        }
+    }
+
+    fn error(&self, e: Error) -> LexError {
+        let offset = self.source.len() - e.input.len();
+        let (lines, line_start) = line_breaks(&self.source[..offset]);
+        let location = Location {
+            chunk: self.chunk.clone(),
+            line: lines + 1,
+            col: offset - line_start.unwrap_or(0) + 1,
+        };
+
+        match e.kind {
+            ErrorKind::Nom => LexError::UnexpectedChar {
+                location,
+                actual: e.input.first().copied().unwrap_or_default(),
+            },
+            ErrorKind::UnfinishedString => LexError::UnfinishedString(location),
+            ErrorKind::UnfinishedLongString => LexError::UnfinishedLongString(location),
+            ErrorKind::UnfinishedLongComment => LexError::UnfinishedLongComment(location),
+            ErrorKind::MalformedNumber => LexError::MalformedNumber(location),
+            ErrorKind::InvalidEscape(reason) => LexError::InvalidEscape { location, reason },
+        }
+    }
 }
 
-fn lex_integer(input: &str) -> IResult<&str, Token> {
-    map_res(recognize(preceded(opt(char('-')), digit1)), |s: &str| {
-        s.parse().map(Token::Integer)
-    })(input)
+/// Yield tokens until `Eof` (inclusive) or the first error.
+impl Iterator for Lexer<'_> {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let token = self.next_token();
+        self.done = !matches!(&token, Ok(token) if token.kind != TokenKind::Eof);
+        Some(token)
+    }
 }
 
-fn lex_float(input: &str) -> IResult<&str, Token> {
-    map_res(
-        alt((
-            // Case one: .42
-            recognize(tuple((
-                char('.'),
-                digit1,
-                opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
-            ))),
-            // Case two: 42e42 and 42.42e42
-            recognize(tuple((
-                digit1,
-                opt(preceded(char('.'), digit1)),
-                one_of("eE"),
-                opt(one_of("+-")),
-                digit1,
-            ))),
-            // Case three: 42. and 42.42
-            recognize(tuple((digit1, char('.'), opt(digit1)))),
-        )),
-        |s: &str| s.parse().map(Token::Float),
-    )(input)
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.chunk, self.line, self.col)
+    }
+}
+
+fn lex(input: &[u8]) -> LexResult<'_, TokenKind> {
+    alt((
+        lex_string,
+        lex_number,
+        lex_word,
+        lex_chars,
+        value(TokenKind::Eof, eof),
+    ))(input)
 }
 
-fn lex_word(input: &str) -> IResult<&str, Token> {
-    take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_')(input).map(|(input, output)| {
+fn lex_trivia(input: &[u8]) -> LexResult<'_, TriviaKind> {
+    alt((
+        value(
+            TriviaKind::Whitespace,
+            // 与 C 的 isspace 一致，包括 \v 和 \f
+            take_while1(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r' | b'\x0B' | b'\x0C')),
+        ),
+        lex_comment,
+    ))(input)
+}
+
+fn shebang(input: &[u8]) -> LexResult<'_, &[u8]> {
+    recognize(preceded(char('#'), opt(is_not("\r\n"))))(input)
+}
+
+/// Count the line breaks in `text`, where `\r\n` and `\n\r` count as one,
+/// returning the count and the offset past the last one.
+fn line_breaks(text: &[u8]) -> (usize, Option<usize>) {
+    let mut lines = 0;
+    let mut line_start = None;
+    let mut i = 0;
+    while i < text.len() {
+        if let c @ (b'\n' | b'\r') = text[i] {
+            lines += 1;
+            i += 1;
+            if matches!(text.get(i), Some(&next) if next != c && matches!(next, b'\n' | b'\r')) {
+                i += 1;
+            }
+            line_start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    (lines, line_start)
+}
+
+fn lex_word(input: &[u8]) -> LexResult<'_, TokenKind> {
+    take_while1(|c: u8| c.is_ascii_alphanumeric() || c == b'_')(input).map(|(input, output)| {
+        // 名字只由 ASCII 字符组成
+        let output = str::from_utf8(output).unwrap();
         (
             input,
             UNIT_TOKEN
                 .get(output)
                 .cloned()
-                .unwrap_or_else(|| Token::Name(SmolStr::new(output))),
+                .unwrap_or_else(|| TokenKind::Name(SmolStr::new(output))),
         )
     })
 }
 
-fn lex_chars(input: &str) -> IResult<&str, Token> {
+fn lex_chars(input: &[u8]) -> LexResult<'_, TokenKind> {
     alt((
         tag("<<"),
         tag(">>"),
@@ -202,16 +438,28 @@ fn lex_chars(input: &str) -> IResult<&str, Token> {
         tag("<="),
         tag(">="),
         tag("::"),
-        tag(".."),
+        // 较长的符号优先匹配
         tag("..."),
+        tag(".."),
         recognize(one_of("+-*/%^#&~|<>=(){}[];:,.")),
     ))(input)
-    .map(|(input, output)| (input, UNIT_TOKEN.get(output).cloned().unwrap()))
+    .map(|(input, output)| {
+        let output = str::from_utf8(output).unwrap();
+        (input, UNIT_TOKEN.get(output).cloned().unwrap())
+    })
 }
 
-fn lex_comment(input: &str) -> IResult<&str, Token> {
-    value(
-        Token::Comment,
-        delimited(tag("--"), is_not("\n"), char('\n')),
+fn lex_comment(input: &[u8]) -> LexResult<'_, TriviaKind> {
+    preceded(
+        tag("--"),
+        alt((
+            // 长注释：--[[ ... ]]
+            value(
+                TriviaKind::LongComment,
+                cause(ErrorKind::UnfinishedLongComment, long_bracket),
+            ),
+            // 短注释：-- ...，不包括换行符
+            value(TriviaKind::LineComment, opt(is_not("\r\n"))),
+        )),
     )(input)
 }
@@ -0,0 +1,152 @@
+use std::str;
+
+use nom::error::ParseError;
+
+use super::error::{fail, Error, ErrorKind};
+use super::{LexResult, TokenKind};
+
+/// Parse a numeral of the Lua 5.4 grammar, e.g. `42`, `3.`, `.5e-3`, `0xFF` and `0xA.8p0`.
+///
+/// Hexadecimal integers wrap around modulo 2^64,
+/// while decimal integers overflowing `i64` become floats.
+pub fn lex_number(input: &[u8]) -> LexResult<'_, TokenKind> {
+    let (rest, numeral) = numeral(input)?;
+
+    // 数字只由 ASCII 字符组成
+    let numeral = str::from_utf8(numeral).unwrap();
+    let Some(token) = numeral2number(numeral) else {
+        return fail(input, ErrorKind::MalformedNumber);
+    };
+
+    Ok((rest, token))
+}
+
+/// Convert a string to a number the way Lua coerces strings in arithmetic,
+/// allowing surrounding whitespace and a leading sign, e.g. `" -0x10 "`.
+pub fn str2number(s: &[u8]) -> Option<TokenKind> {
+    let s = s.trim_ascii();
+    let (neg, s) = match s {
+        [b'-', s @ ..] => (true, s),
+        [b'+', s @ ..] => (false, s),
+        s => (false, s),
+    };
+
+    // 数字必须占满整个字符串
+    let Ok((b"", numeral)) = numeral(s) else {
+        return None;
+    };
+    match numeral2number(str::from_utf8(numeral).unwrap())? {
+        TokenKind::Integer(i) if neg => Some(TokenKind::Integer(i.wrapping_neg())),
+        TokenKind::Float(f) if neg => Some(TokenKind::Float(-f)),
+        token => Some(token),
+    }
+}
+
+/// Recognize a numeral greedily like Lua does,
+/// so that a malformed one like `3..2` or `0xg` is rejected as a whole.
+fn numeral(input: &[u8]) -> LexResult<'_, &[u8]> {
+    if !matches!(input, [b'0'..=b'9', ..] | [b'.', b'0'..=b'9', ..]) {
+        return Err(nom::Err::Error(Error::from_error_kind(
+            input,
+            nom::error::ErrorKind::Digit,
+        )));
+    }
+
+    let (mut i, expo) = match input {
+        [b'0', b'x' | b'X', ..] => (2, [b'p', b'P']),
+        _ => (0, [b'e', b'E']),
+    };
+    while let Some(&c) = input.get(i) {
+        if expo.contains(&c) {
+            i += 1;
+            // 指数可以带符号
+            if let Some(b'+' | b'-') = input.get(i) {
+                i += 1;
+            }
+        } else if c.is_ascii_hexdigit() || c == b'.' {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    // 紧随其后的字母数字也算作数字的一部分，以便报告格式错误
+    while let Some(c) = input.get(i) {
+        if c.is_ascii_alphanumeric() || *c == b'_' {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+
+    Ok((&input[i..], &input[..i]))
+}
+
+fn numeral2number(s: &str) -> Option<TokenKind> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        if !hex.is_empty() && hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            let i = hex.bytes().fold(0u64, |i, c| {
+                i.wrapping_mul(16)
+                    .wrapping_add((c as char).to_digit(16).unwrap() as u64)
+            });
+            Some(TokenKind::Integer(i as i64))
+        } else {
+            hex_float(hex).map(TokenKind::Float)
+        }
+    } else if s.bytes().all(|c| c.is_ascii_digit()) {
+        s.parse()
+            .map(TokenKind::Integer)
+            .or_else(|_| s.parse().map(TokenKind::Float))
+            .ok()
+    } else {
+        s.parse().map(TokenKind::Float).ok()
+    }
+}
+
+/// Convert the part after `0x` of a hexadecimal float, e.g. `A.8p-1`.
+fn hex_float(s: &str) -> Option<f64> {
+    let (mantissa, exp) = match s.find(['p', 'P']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+
+    let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if int.is_empty() && frac.is_empty() {
+        return None;
+    }
+
+    let mut m = 0.0;
+    for c in int.chars().chain(frac.chars()) {
+        m = m * 16.0 + c.to_digit(16)? as f64;
+    }
+    if m == 0.0 {
+        // 否则零乘以无穷会得到 NaN
+        return Some(0.0);
+    }
+
+    let mut e = match exp {
+        Some(exp) => {
+            let digits = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+            if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            // 指数过大时，结果必然为零或无穷
+            exp.parse::<i32>().unwrap_or(if exp.starts_with('-') {
+                i32::MIN / 2
+            } else {
+                i32::MAX / 2
+            })
+        }
+        None => 0,
+    };
+    // 每位小数相当于除以 16
+    e = e.saturating_sub(frac.len() as i32 * 4);
+
+    // `m`不小于 1，`e`过大时结果总为无穷；但`2f64.powi(e)`在`e`过小时为零，
+    // 而`m`可能很大，所以像 ldexp 一样分段缩放，且中间结果保持为规格化数，只在最后一步舍入
+    let step = f64::MIN_EXP - 1 + f64::MANTISSA_DIGITS as i32;
+    while e < f64::MIN_EXP - 1 && m != 0.0 {
+        m *= 2f64.powi(step);
+        e -= step;
+    }
+    Some(m * 2f64.powi(e))
+}
@@ -2,66 +2,117 @@ use std::str;
 
 use nom::{
     branch::alt,
-    bytes::{
-        complete::{is_not, take, take_while},
-        streaming::take_while_m_n,
-    },
-    character::{complete::char, streaming::multispace1},
-    combinator::{map, map_res, value, verify},
-    multi::fold_many0,
-    sequence::{delimited, preceded},
-    IResult, Parser,
+    bytes::complete::{is_not, tag, take_while, take_while1, take_while_m_n},
+    character::complete::char,
+    combinator::{cut, map, map_res, opt, value, verify},
+    multi::{fold_many0, many0_count},
+    sequence::{delimited, preceded, terminated},
 };
 use tinyvec::TinyVec;
 
-use super::Token;
+use super::error::{cause, fail, ErrorKind};
+use super::{LexResult, TokenKind};
 use crate::str::LossyStr;
 
-pub fn lex_string(input: &str) -> IResult<&str, Token> {
-    let build_string = fold_many0(
-        fragment,
-        TinyVec::<[u8; LossyStr::INLINE_CAP]>::new,
-        |mut string, fragment| {
-            match fragment {
-                StringFragment::Literal(s) => string.extend_from_slice(s.as_bytes()),
-                StringFragment::EscapedChar(c) => string.push(c),
-                StringFragment::EscapedWS => {}
-            }
-            string
-        },
-    );
+pub fn lex_string(input: &[u8]) -> LexResult<'_, TokenKind> {
+    alt((
+        quoted_string('"'),
+        quoted_string('\''),
+        map(
+            cause(ErrorKind::UnfinishedLongString, long_bracket),
+            |s: &[u8]| TokenKind::String(normalize_line_breaks(s)),
+        ),
+    ))(input)
+}
 
-    map(delimited(char('"'), build_string, char('"')), Token::String)(input)
+/// Parse a long bracket of any level, e.g. `[[...]]` or `[==[...]==]`,
+/// returning its content.
+///
+/// A line break immediately following the opening bracket is skipped.
+pub fn long_bracket(input: &[u8]) -> LexResult<'_, &[u8]> {
+    let (input, level) = delimited(char('['), many0_count(char('=')), char('['))(input)?;
+    let (input, _) = opt(line_break)(input)?;
+
+    let close = [&b"]"[..], &b"=".repeat(level), b"]"].concat();
+    match input.windows(close.len()).position(|w| w == close) {
+        Some(end) => Ok((&input[end + close.len()..], &input[..end])),
+        // 开括号已匹配，找不到闭括号则不再回溯
+        None => fail(input, ErrorKind::Nom),
+    }
+}
+
+/// Parse a short string delimited by `quote`, which is either `"` or `'`.
+fn quoted_string(quote: char) -> impl FnMut(&[u8]) -> LexResult<'_, TokenKind> {
+    move |input| {
+        let build_string = fold_many0(
+            fragment(quote),
+            TinyVec::<[u8; LossyStr::INLINE_CAP]>::new,
+            |mut string, fragment| {
+                match fragment {
+                    StringFragment::Literal(s) => string.extend_from_slice(s),
+                    StringFragment::EscapedChar(c) => string.push(c),
+                    StringFragment::EscapedUtf8(c) => utf8_encode(&mut string, c),
+                    StringFragment::EscapedWS => {}
+                }
+                string
+            },
+        );
+
+        map(
+            preceded(
+                char(quote),
+                terminated(
+                    build_string,
+                    cause(ErrorKind::UnfinishedString, cut(char(quote))),
+                ),
+            ),
+            TokenKind::String,
+        )(input)
+    }
 }
 
 /// A string fragment contains a fragment of a string being parsed:
 ///
 /// - a non-empty Literal (a series of non-escaped characters)
 /// - a single parsed Escaped Character
+/// - a code point given by `\u{XXX}`, to be encoded in UTF-8
 /// - a block of Escaped Whitespace
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum StringFragment<'a> {
-    Literal(&'a str),
+    Literal(&'a [u8]),
     EscapedChar(u8),
+    EscapedUtf8(u32),
     EscapedWS,
 }
 
-fn fragment(input: &str) -> IResult<&str, StringFragment<'_>> {
-    alt((
-        map(literal, StringFragment::Literal),
-        map(escaped_char, StringFragment::EscapedChar),
-        value(StringFragment::EscapedWS, escaped_whitespace),
-    ))(input)
+fn fragment(quote: char) -> impl FnMut(&[u8]) -> LexResult<'_, StringFragment<'_>> {
+    move |input| {
+        alt((
+            map(|i| literal(i, quote), StringFragment::Literal),
+            cause(
+                ErrorKind::InvalidEscape("invalid escape sequence"),
+                preceded(
+                    char('\\'),
+                    cut(alt((
+                        map(escaped_char, StringFragment::EscapedChar),
+                        map(escaped_utf8, StringFragment::EscapedUtf8),
+                        value(StringFragment::EscapedWS, escaped_whitespace),
+                    ))),
+                ),
+            ),
+        ))(input)
+    }
 }
 
-/// Parse a non-empty block of text that doesn't include \ or "
-fn literal(input: &str) -> IResult<&str, &str> {
+/// Parse a non-empty block of text that doesn't include \, the quote or a line break
+fn literal(input: &[u8], quote: char) -> LexResult<'_, &[u8]> {
+    let stops = if quote == '"' { "\"\\\r\n" } else { "'\\\r\n" };
     // 若输入满足`F`，则用`G`验证，通过则返回输入，否则返回验证错误；
     // 若输入不满足`F`，则返回`F`的错误。
-    verify(is_not(r#""\"#), |s: &str| !s.is_empty())(input)
+    verify(is_not(stops), |s: &[u8]| !s.is_empty())(input)
 }
 
-/// Parse an escaped character
+/// Parse an escaped character, the leading backslash has been consumed
 // \a   bell
 // \b   back space
 // \f   form feed
@@ -72,40 +123,132 @@ fn literal(input: &str) -> IResult<&str, &str> {
 // \\   backslash
 // \"   double quote
 // \'   single quote
+// \<line break>    newline
 // \nnn byte (0 ~ 255)
-fn escaped_char(input: &str) -> IResult<&str, u8> {
+// \xXX byte (00 ~ FF)
+fn escaped_char(input: &[u8]) -> LexResult<'_, u8> {
+    alt((
+        value(b'\n', char('n')),
+        value(b'\r', char('r')),
+        value(b'\t', char('t')),
+        value(b'\x07', char('a')),
+        value(b'\x08', char('b')),
+        value(b'\x0B', char('v')),
+        value(b'\x0C', char('f')),
+        value(b'\n', line_break),
+        dec_byte,
+        hex_byte,
+        value(b'\\', char('\\')),
+        value(b'\'', char('\'')),
+        value(b'"', char('"')),
+    ))(input)
+}
+
+/// Parse `z` followed by any amount of whitespace, including line breaks.
+/// This is used to discard the escaped whitespace.
+fn escaped_whitespace(input: &[u8]) -> LexResult<'_, &[u8]> {
+    // 与 Lua 的 isspace 一致，包括`\v`和`\f`
     preceded(
-        char('\\'),
-        alt((
-            value(b'\n', char('n')),
-            value(b'\r', char('r')),
-            value(b'\t', char('t')),
-            value(b'\x07', char('a')),
-            value(b'\x08', char('b')),
-            value(b'\x0B', char('v')),
-            value(b'\x0C', char('f')),
-            dec_byte,
-            hex_byte,
-            value(b'\\', char('\\')),
-            value(b'\'', char('\'')),
-            value(b'"', char('"')),
-        )),
+        char('z'),
+        take_while(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r' | 0x0B | 0x0C)),
     )(input)
 }
 
-/// Parse a backslash, followed by any amount of whitespace.
-/// This is used to discard any escaped whitespace.
-fn escaped_whitespace(input: &str) -> IResult<&str, &str> {
-    preceded(char('\\'), multispace1)(input)
+/// Parse `u{XXX}` where `XXX` is a code point no greater than 2^31,
+/// the leading backslash has been consumed
+fn escaped_utf8(input: &[u8]) -> LexResult<'_, u32> {
+    let hex = cause(
+        ErrorKind::InvalidEscape("hexadecimal digit expected"),
+        cut(take_while1(|c: u8| c.is_ascii_hexdigit())),
+    );
+    let code_point = cause(
+        ErrorKind::InvalidEscape("UTF-8 value too large"),
+        cut(map_res(hex, |s: &[u8]| {
+            u32::from_str_radix(str::from_utf8(s).unwrap(), 16)
+                .ok()
+                .filter(|&c| c <= 0x7FFF_FFFF)
+                .ok_or(())
+        })),
+    );
+    preceded(
+        char('u'),
+        delimited(
+            cause(
+                ErrorKind::InvalidEscape("missing '{' in \\u{xxxx}"),
+                cut(char('{')),
+            ),
+            code_point,
+            cause(
+                ErrorKind::InvalidEscape("missing '}' in \\u{xxxx}"),
+                cut(char('}')),
+            ),
+        ),
+    )(input)
 }
 
-fn dec_byte(input: &str) -> IResult<&str, u8> {
-    let dec = take_while_m_n(1, 3, |n: char| n.is_ascii_digit());
-    map_res(dec, |s: &str| s.parse())(input)
+fn dec_byte(input: &[u8]) -> LexResult<'_, u8> {
+    let (rest, dec) = take_while_m_n(1, 3, |n: u8| n.is_ascii_digit())(input)?;
+    match str::from_utf8(dec).unwrap().parse() {
+        Ok(b) => Ok((rest, b)),
+        Err(_) => fail(input, ErrorKind::InvalidEscape("decimal escape too large")),
+    }
 }
 
-fn hex_byte(input: &str) -> IResult<&str, u8> {
-    let hex = take(2usize).and_then(take_while(|n: char| n.is_ascii_hexdigit()));
-    let preceded_hex = preceded(char('x'), hex);
-    map_res(preceded_hex, |s: &str| u8::from_str_radix(s, 16))(input)
+fn hex_byte(input: &[u8]) -> LexResult<'_, u8> {
+    let hex = take_while_m_n(2, 2, |n: u8| n.is_ascii_hexdigit());
+    let preceded_hex = preceded(
+        char('x'),
+        cause(
+            ErrorKind::InvalidEscape("hexadecimal digit expected"),
+            cut(hex),
+        ),
+    );
+    map_res(preceded_hex, |s: &[u8]| {
+        u8::from_str_radix(str::from_utf8(s).unwrap(), 16)
+    })(input)
+}
+
+/// Parse a line break, where `\r\n` and `\n\r` count as one.
+pub fn line_break(input: &[u8]) -> LexResult<'_, &[u8]> {
+    alt((tag("\r\n"), tag("\n\r"), tag("\n"), tag("\r")))(input)
+}
+
+/// Convert every line break in a long string into `\n`.
+fn normalize_line_breaks(mut s: &[u8]) -> TinyVec<[u8; LossyStr::INLINE_CAP]> {
+    let mut string = TinyVec::new();
+    while let Some(i) = s.iter().position(|&c| c == b'\n' || c == b'\r') {
+        string.extend_from_slice(&s[..i]);
+        string.push(b'\n');
+        let (rest, _) = line_break(&s[i..]).unwrap();
+        s = rest;
+    }
+    string.extend_from_slice(s);
+    string
+}
+
+/// Encode a code point (up to 2^31) into an extended UTF-8 sequence
+/// of at most 6 bytes, the same way as Lua does.
+fn utf8_encode(string: &mut TinyVec<[u8; LossyStr::INLINE_CAP]>, mut c: u32) {
+    if c < 0x80 {
+        string.push(c as u8);
+        return;
+    }
+
+    let mut buf = [0u8; 6];
+    let mut n = 0;
+    // 首字节尚能容纳的最大值
+    let mut mfb = 0x3f;
+    loop {
+        n += 1;
+        buf[buf.len() - n] = 0x80 | (c & 0x3f) as u8;
+        c >>= 6;
+        mfb >>= 1;
+        if c <= mfb {
+            break;
+        }
+    }
+    n += 1;
+    buf[buf.len() - n] = ((!mfb << 1) | c) as u8;
+
+    string.extend_from_slice(&buf[buf.len() - n..]);
 }
@@ -1,23 +1,41 @@
 #[cfg(test)]
 mod tests;
 
+mod arith;
 mod bytecode;
+mod debug;
 mod lex;
 mod parse;
 mod str;
+mod table;
 mod value;
 mod vm;
 
+pub use self::lex::{LexError, Lexer, Location, Span, Token, TokenKind, Trivia, TriviaKind};
+
 pub(crate) use self::{
     bytecode::{ByteCode, ByteCodeStack},
-    lex::{LexError, Lexer, Token},
-    parse::ParseProto,
-    value::Value,
+    parse::{FuncProto, ParseProto},
+    table::Table,
+    value::{LuaClosure, Upvalue, Value},
     vm::ExeState,
 };
 
 pub fn rua(source: &str) -> anyhow::Result<()> {
-    let proto = ParseProto::new(source).parse()?;
+    rua_bytes("main", source.as_bytes())
+}
+
+/// Run the chunk named `chunk`, whose source is a byte string
+/// that is not necessarily UTF-8.
+pub fn rua_bytes(chunk: &str, source: &[u8]) -> anyhow::Result<()> {
+    rua_args(chunk, source, &[])
+}
+
+/// Run the chunk like [`rua_bytes`], passing `args` to it as `...`,
+/// the way a script receives its command-line arguments.
+pub fn rua_args(chunk: &str, source: &[u8], args: &[&[u8]]) -> anyhow::Result<()> {
+    let proto = ParseProto::new(chunk, source).parse()?;
     let mut state = ExeState::new();
-    state.execute(proto)
+    let args = args.iter().map(|&arg| Value::String(arg.into())).collect();
+    state.execute(proto, args)
 }
@@ -1,222 +1,1724 @@
+use std::rc::Rc;
+use std::{iter, mem};
+
 use smol_str::SmolStr;
 
 use self::error::{bail, expect_next};
-use crate::{ByteCode, ByteCodeStack, Lexer, Token, Value};
+use crate::str::LossyStr;
+use crate::{ByteCode, ByteCodeStack, Lexer, Token, TokenKind, Value};
 
 #[derive(Debug)]
 pub struct ParseProto<'a> {
+    pub lexer: Lexer<'a>,
+    /// 正在解析的函数
+    fs: FuncState,
+    /// 外层函数，最内层的在最后
+    enclosing: Vec<FuncState>,
+}
+
+/// The compiled prototype of a function, including the main chunk.
+#[derive(Debug, Default)]
+pub struct FuncProto {
     pub constants: Vec<Value>,
     pub bytecodes: Vec<ByteCode>,
-    pub lexer: Lexer<'a>,
-    pub locals: Vec<SmolStr>,
+    /// 固定参数的个数
+    pub nparam: usize,
+    /// 是否接受变长参数
+    pub is_vararg: bool,
+    /// 创建闭包时如何获取各个上值
+    pub upvalues: Vec<UpvalDesc>,
+    /// 内层函数的原型
+    pub protos: Vec<Rc<FuncProto>>,
+    /// 各个上值的名字，用于错误信息
+    pub upvalue_names: Vec<SmolStr>,
+    /// 局部变量的作用域，用于错误信息
+    pub locals: Vec<LocalInfo>,
+}
+
+/// Debug information of a local variable.
+#[derive(Debug)]
+pub struct LocalInfo {
+    pub name: SmolStr,
+    pub reg: usize,
+    /// 变量生效的字节码范围
+    pub start_pc: usize,
+    pub end_pc: usize,
+}
+
+/// Where a closure captures an upvalue from, when it is created by the enclosing function.
+#[derive(Debug, Clone, Copy)]
+pub enum UpvalDesc {
+    /// 外层函数的局部变量所在的寄存器
+    Local(usize),
+    /// 外层函数的上值索引
+    Upvalue(usize),
+}
+
+/// The parsing state of a function.
+#[derive(Debug, Default)]
+struct FuncState {
+    constants: Vec<Value>,
+    bytecodes: Vec<ByteCode>,
+    locals: Vec<LocalVar>,
+    upvalues: Vec<(SmolStr, UpvalDesc)>,
+    protos: Vec<Rc<FuncProto>>,
+    is_vararg: bool,
+    /// 已离开作用域的局部变量
+    local_infos: Vec<LocalInfo>,
+    /// 第一个空闲寄存器，局部变量之上的寄存器用于存放临时值
+    sp: usize,
+    /// 由外到内的各层循环
+    break_blocks: Vec<BreakBlock>,
+}
+
+#[derive(Debug)]
+struct LocalVar {
+    name: SmolStr,
+    /// 是否被内层函数捕获为上值，离开作用域时需要关闭
+    captured: bool,
+    /// 变量生效处的字节码位置
+    start_pc: usize,
+}
+
+/// The `break`s in a loop.
+#[derive(Debug)]
+struct BreakBlock {
+    /// 循环开始前的局部变量个数
+    nvar: usize,
+    /// 循环内是否有局部变量被捕获
+    captured: bool,
+    /// `break`对应的 Jump 字节码位置
+    jumps: Vec<usize>,
+}
+
+/// Description of a parsed expression, whose bytecodes are not generated
+/// until we know where its value goes.
+#[derive(Debug)]
+enum ExpDesc {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(LossyStr),
+    /// 值位于栈上的寄存器中
+    Local(usize),
+    /// 全局变量名在常量表中的索引
+    Global(usize),
+    /// 上值索引
+    Upvalue(usize),
+    /// 表与键都位于寄存器中的索引`t[k]`
+    Index(usize, usize),
+    /// 表位于寄存器中，字符串键在常量表中的索引`t.k`
+    IndexField(usize, usize),
+    /// 表位于寄存器中，键为较小整数的索引`t[i]`
+    IndexInt(usize, u8),
+    /// 内层函数原型的索引
+    Function(usize),
+    /// 一元运算，操作数位于寄存器中
+    UnaryOp(fn(u8, u8) -> ByteCode, usize),
+    /// 二元运算，两个操作数都位于寄存器中
+    BinaryOp(fn(u8, u8, u8) -> ByteCode, usize, usize),
+    /// 变长参数`...`，值的个数由所在的位置决定
+    VarArgs,
+    /// 函数调用，包括函数所在的寄存器和参数个数加一，
+    /// 参数个数不定时为 0；返回值的个数由所在的位置决定
+    Call(usize, usize),
 }
 
 impl<'a> ParseProto<'a> {
-    pub fn new(source: &'a str) -> Self {
+    pub fn new(chunk: &str, source: &'a [u8]) -> Self {
         Self {
-            constants: Vec::default(),
-            bytecodes: Vec::default(),
-            lexer: Lexer::new(source),
-            locals: Vec::default(),
+            lexer: Lexer::new(chunk, source),
+            // 主函数接受变长参数，如命令行参数
+            fs: FuncState {
+                is_vararg: true,
+                ..FuncState::default()
+            },
+            enclosing: Vec::new(),
         }
     }
 
-    pub fn parse(mut self) -> anyhow::Result<Self> {
-        loop {
-            let code = match self.lexer.next()? {
-                Token::Local => {
-                    expect_next!(self.lexer, Token::Name(var), "<variable>");
-                    expect_next!(self.lexer, Token::Assign, "`=`");
-                    let code = self.load_exp(self.locals.len() as u8)?;
-                    self.locals.push(var);
-                    code
-                }
-                Token::Name(name) => match self.lexer.next()? {
-                    Token::Assign => self.assign(name),
-                    t => self.call_function(t, name),
-                }?,
-                Token::Eof => break,
-                Token::Comment => continue,
-                t => bail!(t),
-            };
-            self.bytecodes.push(code);
+    /// Parse the main chunk.
+    pub fn parse(mut self) -> anyhow::Result<FuncProto> {
+        let end = self.block()?;
+        if end.kind != TokenKind::Eof {
+            return Err(ParseError::from(UnexpectedTokenError::new(
+                self.lexer.location(&end.span),
+                end.kind,
+                "<eof>",
+            ))
+            .into());
         }
 
-        tracing::debug!("constants: {:#?}", self.constants);
-        tracing::debug!("bytecode stack: [\n{}]", ByteCodeStack(&self.bytecodes));
+        self.check_limits()?;
+        Ok(self.fs.into_proto(0))
+    }
 
-        Ok(self)
+    /// Parse statements until a token which ends the block, and return that token.
+    /// Locals declared in the block go out of scope at its end.
+    fn block(&mut self) -> Result<Token, ParseError> {
+        let nvar = self.fs.locals.len();
+        let end = self.block_scope()?;
+        self.close_locals(nvar);
+
+        Ok(end)
+    }
+
+    /// Put the locals from the `nvar`-th out of scope,
+    /// closing their upvalues if any of them is captured.
+    fn close_locals(&mut self, nvar: usize) {
+        if self.fs.locals[nvar..].iter().any(|var| var.captured) {
+            self.fs.bytecodes.push(ByteCode::Close(nvar as u8));
+        }
+        self.fs.end_locals(nvar);
+    }
+
+    /// Bring locals named `names` into scope from the current bytecode on.
+    fn add_locals(&mut self, names: impl IntoIterator<Item = SmolStr>) {
+        let start_pc = self.fs.bytecodes.len();
+        self.fs
+            .locals
+            .extend(names.into_iter().map(|name| LocalVar::new(name, start_pc)));
+    }
+
+    /// Parse statements like [`Self::block`], but leave the locals in scope.
+    //
+    // block ::= {stat}
+    fn block_scope(&mut self) -> Result<Token, ParseError> {
+        loop {
+            // 语句之间不保留临时值
+            self.fs.sp = self.fs.locals.len();
+
+            let token = self.lexer.next_token()?;
+            match token.kind {
+                TokenKind::SemiColon => {}
+                TokenKind::Local => self.local()?,
+                TokenKind::Do => self.do_stat()?,
+                TokenKind::Function => self.function_stat()?,
+                TokenKind::If => self.if_stat()?,
+                TokenKind::While => self.while_stat()?,
+                TokenKind::Repeat => self.repeat_stat()?,
+                TokenKind::For => self.for_stat()?,
+                TokenKind::Break => self.break_stat(token)?,
+                TokenKind::Return => return self.return_stat(),
+                TokenKind::Name(_) | TokenKind::ParL => self.exp_stat(token)?,
+                _ if is_block_end(&token.kind) => return Ok(token),
+                _ => bail!(self.lexer, token),
+            }
+        }
     }
 
     fn add_const(&mut self, value: Value) -> usize {
-        self.constants
+        self.fs
+            .constants
             .iter()
-            .position(|v| v == &value)
+            .position(|v| match (v, &value) {
+                // 按位比较浮点数，以区分 0.0 与 -0.0
+                (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+                (v, value) => v == value,
+            })
             .unwrap_or_else(|| {
-                self.constants.push(value);
-                self.constants.len() - 1
+                self.fs.constants.push(value);
+                self.fs.constants.len() - 1
             })
     }
 
     fn load_const(&mut self, dst: u8, constant: Value) -> ByteCode {
-        ByteCode::LoadConst(dst, self.add_const(constant) as u8)
+        ByteCode::LoadConst(dst, self.add_const(constant) as u16)
     }
 
-    fn load_exp(&mut self, dst: u8) -> Result<ByteCode, ParseError> {
-        let code = match self.lexer.next()? {
-            Token::Nil => ByteCode::LoadNil(dst),
-            Token::True => ByteCode::LoadBool(dst, true),
-            Token::False => ByteCode::LoadBool(dst, false),
-            Token::Integer(i) => {
-                if let Ok(i) = i16::try_from(i) {
-                    ByteCode::LoadInt(dst, i)
-                } else {
-                    self.load_const(dst, Value::Integer(i))
-                }
+    fn local(&mut self) -> Result<(), ParseError> {
+        if self.lexer.peek()?.kind == TokenKind::Function {
+            self.lexer.next_token()?;
+            return self.local_function();
+        }
+
+        let vars = self.namelist()?;
+        let ibase = self.fs.locals.len();
+        if self.lexer.peek()?.kind == TokenKind::Assign {
+            self.lexer.next_token()?;
+            self.explist_want(vars.len())?;
+        } else {
+            for i in 0..vars.len() {
+                self.discharge(ibase + i, ExpDesc::Nil);
             }
-            Token::Float(f) => self.load_const(dst, Value::Float(f)),
-            Token::String(s) => self.load_const(dst, Value::String(s.into())),
-            Token::Name(name) => self.load_var(dst, name),
-            t => bail!(t, "<expression>"),
-        };
+        }
+        // 新变量在初始化表达式之后才生效，如`local x = x`
+        self.add_locals(vars);
 
-        Ok(code)
+        Ok(())
     }
 
-    fn load_var(&mut self, dst: u8, name: SmolStr) -> ByteCode {
-        // 优先查找后定义的变量，即作用域遮蔽
-        if let Some(src) = self.local_var(&name) {
-            ByteCode::Move(dst, src as u8)
-        } else {
-            ByteCode::GetGlobal(dst, self.add_const(Value::Identifier(name)) as u8)
+    // namelist ::= Name {`,` Name}
+    fn namelist(&mut self) -> Result<Vec<SmolStr>, ParseError> {
+        let mut names = Vec::new();
+        loop {
+            expect_next!(self.lexer, TokenKind::Name(name), "<variable>");
+            names.push(name);
+            if self.lexer.peek()?.kind != TokenKind::Comma {
+                return Ok(names);
+            }
+            self.lexer.next_token()?;
         }
     }
 
-    // <local>  = <const>   把常量加载到栈上指定位置，对应字节码 Load*
-    // <local>  = <local>   复制栈上值，对应字节码 Move
-    // <local>  = <global>  把栈上值赋值给全局变量，对应字节码 GetGlobal
+    // <local>  = <exp>     把表达式的值放到局部变量所在的寄存器
     // <global> = <const>   把常量赋值给全局变量，需要首先把常量加到常量表中，然后通过字节码 SetGlobalConst 完成赋值
-    // <global> = <local>   把局部变量赋值给全局变量，对应字节码 SetGlobal
+    // <global> = <local>   把局部变量赋值给全局变量，对应字节码 SetGlobalLocal
     // <global> = <global>  把全局变量赋值给全局变量，对应字节码 SetGlobalGlobal
-    fn assign(&mut self, var: SmolStr) -> Result<ByteCode, ParseError> {
-        if let Some(src) = self.local_var(&var) {
+    // <global> = <exp>     先把表达式的值放到临时寄存器，再通过字节码 SetGlobalLocal 完成赋值
+    // <upvalue> = <exp>    先把表达式的值放到寄存器，再通过字节码 SetUpval 完成赋值
+    // <field> = <exp>      先把表达式的值放到寄存器，再通过字节码 SetTable、SetField 或 SetI 完成赋值
+    //
+    // stat ::= varlist `=` explist
+    // varlist ::= var {`,` var}
+    fn assign(&mut self, first: ExpDesc) -> Result<(), ParseError> {
+        let mut vars = vec![first];
+        loop {
+            let token = self.lexer.next_token()?;
+            match token.kind {
+                TokenKind::Assign => break,
+                TokenKind::Comma => {
+                    let token = self.lexer.next_token()?;
+                    let is_name = matches!(token.kind, TokenKind::Name(_));
+                    let var = self.suffixed_exp(token)?;
+                    if !is_assignable(&var, is_name) {
+                        bail!(self.lexer, self.lexer.next_token()?);
+                    }
+                    if let ExpDesc::Local(reg) = var {
+                        self.check_conflict(&mut vars, reg);
+                    }
+                    vars.push(var);
+                }
+                _ => bail!(self.lexer, token, "`=` or `,`"),
+            }
+        }
+
+        let ibase = self.fs.sp;
+        let (n, last) = self.explist()?;
+        if vars.len() == 1 && n == 1 {
+            self.assign_var(vars.pop().unwrap(), last);
+            return Ok(());
+        }
+
+        // 先求出所有的值再赋值，使得`a, b = b, a`可以交换变量
+        self.explist_adjust(ibase, n, last, vars.len());
+        for (i, var) in vars.into_iter().enumerate().rev() {
+            self.assign_var(var, ExpDesc::Local(ibase + i));
+        }
+        Ok(())
+    }
+
+    /// Variables are assigned from right to left, so the local in register `reg`
+    /// may be assigned before a previous variable indexes with it,
+    /// e.g. `t[i], i = 1, 2`. Make such variables use a copy of its old value.
+    fn check_conflict(&mut self, vars: &mut [ExpDesc], reg: usize) {
+        let copy = self.fs.sp;
+        let mut conflict = false;
+        for var in vars {
+            match var {
+                ExpDesc::Index(t, k) => {
+                    if *t == reg {
+                        *t = copy;
+                        conflict = true;
+                    }
+                    if *k == reg {
+                        *k = copy;
+                        conflict = true;
+                    }
+                }
+                ExpDesc::IndexField(t, _) | ExpDesc::IndexInt(t, _) if *t == reg => {
+                    *t = copy;
+                    conflict = true;
+                }
+                _ => {}
+            }
+        }
+
+        if conflict {
+            self.discharge(copy, ExpDesc::Local(reg));
+        }
+    }
+
+    /// Assign `desc` to `var`, which is an assignable expression.
+    fn assign_var(&mut self, var: ExpDesc, desc: ExpDesc) {
+        match var {
             // 正在赋值给局部变量
-            self.load_exp(src as u8)
-        } else {
+            ExpDesc::Local(dst) => self.discharge(dst, desc),
+            // 正在赋值给上值
+            ExpDesc::Upvalue(idx) => {
+                let src = self.discharge_any(desc);
+                self.fs
+                    .bytecodes
+                    .push(ByteCode::SetUpval(idx as u8, src as u8));
+            }
             // 正在赋值给全局变量
-            let gi = self.add_const(Value::Identifier(var)) as u8;
+            ExpDesc::Global(gi) => {
+                let gi = gi as u16;
+                let code = match desc {
+                    ExpDesc::Local(src) => ByteCode::SetGlobalLocal(gi, src as u8),
+                    ExpDesc::Global(src) => ByteCode::SetGlobalGlobal(gi, src as u16),
+                    desc => match desc.into_const() {
+                        Ok(value) => ByteCode::SetGlobalConst(gi, self.add_const(value) as u16),
+                        Err(desc) => ByteCode::SetGlobalLocal(gi, self.discharge_any(desc) as u8),
+                    },
+                };
+                self.fs.bytecodes.push(code);
+            }
+            // 正在赋值给表的字段
+            ExpDesc::Index(t, key) => {
+                let src = self.discharge_any(desc);
+                self.fs
+                    .bytecodes
+                    .push(ByteCode::SetTable(t as u8, key as u8, src as u8));
+            }
+            ExpDesc::IndexField(t, key) => {
+                let src = self.discharge_any(desc);
+                self.fs
+                    .bytecodes
+                    .push(ByteCode::SetField(t as u8, key as u8, src as u8));
+            }
+            ExpDesc::IndexInt(t, i) => {
+                let src = self.discharge_any(desc);
+                self.fs
+                    .bytecodes
+                    .push(ByteCode::SetI(t as u8, i, src as u8));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // stat ::= local function Name funcbody
+    fn local_function(&mut self) -> Result<(), ParseError> {
+        expect_next!(self.lexer, TokenKind::Name(var), "<variable>");
+
+        // 与`local x = exp`不同，变量在函数体之前生效，以便递归调用
+        let dst = self.fs.locals.len();
+        self.add_locals([var]);
+        let desc = self.func_body(false)?;
+        self.discharge(dst, desc);
+
+        Ok(())
+    }
+
+    // stat ::= function funcname funcbody
+    // funcname ::= Name {`.` Name} [`:` Name]
+    fn function_stat(&mut self) -> Result<(), ParseError> {
+        expect_next!(self.lexer, TokenKind::Name(name), "<name>");
+
+        let mut var = self.var(name);
+        let mut has_self = false;
+        loop {
+            match self.lexer.peek()?.kind {
+                TokenKind::Dot => {}
+                // 方法是最后一个名字，它隐含参数`self`
+                TokenKind::Colon => has_self = true,
+                _ => break,
+            }
+            self.lexer.next_token()?;
+            expect_next!(self.lexer, TokenKind::Name(name), "<name>");
+            let itable = self.discharge_any(var);
+            var = self.field(itable, name);
+            if has_self {
+                break;
+            }
+        }
+
+        let desc = self.func_body(has_self)?;
+        self.assign_var(var, desc);
+        Ok(())
+    }
+
+    /// Compile a function body into a new prototype nested in the current function.
+    //
+    // funcbody ::= `(` [parlist] `)` block end
+    // parlist ::= namelist [`,` `...`] | `...`
+    fn func_body(&mut self, has_self: bool) -> Result<ExpDesc, ParseError> {
+        let mut params = Vec::new();
+        if has_self {
+            params.push(SmolStr::new_inline("self"));
+        }
+
+        let mut is_vararg = false;
+        expect_next!(self.lexer, TokenKind::ParL, "`(`");
+        if self.lexer.peek()?.kind != TokenKind::ParR {
+            loop {
+                let token = self.lexer.next_token()?;
+                match token.kind {
+                    TokenKind::Name(param) => params.push(param),
+                    // `...`只能是最后一个参数
+                    TokenKind::Dots => {
+                        is_vararg = true;
+                        break;
+                    }
+                    _ => bail!(self.lexer, token, "<parameter>"),
+                }
+                if self.lexer.peek()?.kind != TokenKind::Comma {
+                    break;
+                }
+                self.lexer.next_token()?;
+            }
+        }
+        expect_next!(self.lexer, TokenKind::ParR, "`)`");
+
+        // 参数是函数体的前几个局部变量
+        let nparam = params.len();
+        let fs = FuncState {
+            locals: params
+                .into_iter()
+                .map(|name| LocalVar::new(name, 0))
+                .collect(),
+            is_vararg,
+            ..FuncState::default()
+        };
+        self.enclosing.push(mem::replace(&mut self.fs, fs));
+
+        let end = self.block()?;
+        if end.kind != TokenKind::End {
+            bail!(self.lexer, end, "`end`");
+        }
+        self.check_limits()?;
+
+        let fs = mem::replace(&mut self.fs, self.enclosing.pop().unwrap());
+        self.fs.protos.push(Rc::new(fs.into_proto(nparam)));
+        Ok(ExpDesc::Function(self.fs.protos.len() - 1))
+    }
+
+    // stat ::= do block end
+    fn do_stat(&mut self) -> Result<(), ParseError> {
+        let end = self.block()?;
+        if end.kind != TokenKind::End {
+            bail!(self.lexer, end, "`end`");
+        }
+        Ok(())
+    }
+
+    // stat ::= if exp then block {elseif exp then block} [else block] end
+    fn if_stat(&mut self) -> Result<(), ParseError> {
+        // 各分支结束后跳出整个 if 语句
+        let mut jmp_ends = Vec::new();
+
+        let mut end = self.cond_block(&mut jmp_ends)?;
+        while end.kind == TokenKind::Elseif {
+            end = self.cond_block(&mut jmp_ends)?;
+        }
+        if end.kind == TokenKind::Else {
+            end = self.block()?;
+        }
+        if end.kind != TokenKind::End {
+            bail!(self.lexer, end, "`end`");
+        }
+
+        let iend = self.fs.bytecodes.len();
+        for i in jmp_ends {
+            self.fs.bytecodes[i] =
+                ByteCode::Jump(self.jump_offset(iend as isize - i as isize - 1)?);
+        }
+
+        Ok(())
+    }
+
+    /// Parse `exp then block` of an `if` or `elseif` branch,
+    /// and return the token which ends the block.
+    //
+    //     Test cond, label
+    //     <block>
+    //     Jump end        只在还有后续分支时生成
+    // label:
+    fn cond_block(&mut self, jmp_ends: &mut Vec<usize>) -> Result<Token, ParseError> {
+        let cond = self.exp()?;
+        let cond = self.discharge_any(cond);
+        expect_next!(self.lexer, TokenKind::Then, "`then`");
+
+        let itest = self.fs.bytecodes.len();
+        self.fs.bytecodes.push(ByteCode::Test(0, 0));
+
+        let end = self.block()?;
+        if matches!(end.kind, TokenKind::Elseif | TokenKind::Else) {
+            self.fs.bytecodes.push(ByteCode::Jump(0));
+            jmp_ends.push(self.fs.bytecodes.len() - 1);
+        }
+
+        let jmp = self.jump_offset(self.fs.bytecodes.len() as isize - itest as isize - 1)?;
+        self.fs.bytecodes[itest] = ByteCode::Test(cond as u8, jmp);
+
+        Ok(end)
+    }
+
+    /// Check that the constants and inner functions of the function being parsed
+    /// can be indexed by the 16-bit operands of bytecodes.
+    fn check_limits(&mut self) -> Result<(), ParseError> {
+        let reason = if self.fs.constants.len() > MAX_CONSTANTS {
+            "too many constants"
+        } else if self.fs.protos.len() > MAX_CONSTANTS {
+            "too many functions"
+        } else {
+            return Ok(());
+        };
+        let span = self.lexer.peek()?.span;
+        Err(SyntaxError::new(self.lexer.location(&span), reason).into())
+    }
+
+    /// Convert the offset of a jump to the width of its operand,
+    /// failing if the code jumped over is too long.
+    fn jump_offset<T: TryFrom<isize>>(&mut self, offset: isize) -> Result<T, ParseError> {
+        match T::try_from(offset) {
+            Ok(offset) => Ok(offset),
+            Err(_) => {
+                let span = self.lexer.peek()?.span;
+                Err(
+                    SyntaxError::new(self.lexer.location(&span), "control structure too long")
+                        .into(),
+                )
+            }
+        }
+    }
+
+    // stat ::= while exp do block end
+    //
+    // start:
+    //     Test cond, end
+    //     <block>
+    //     Jump start
+    // end:
+    fn while_stat(&mut self) -> Result<(), ParseError> {
+        let istart = self.fs.bytecodes.len();
+
+        let cond = self.exp()?;
+        let cond = self.discharge_any(cond);
+        expect_next!(self.lexer, TokenKind::Do, "`do`");
+
+        let itest = self.fs.bytecodes.len();
+        self.fs.bytecodes.push(ByteCode::Test(0, 0));
+
+        let nvar = self.fs.locals.len();
+        self.fs.break_blocks.push(BreakBlock::new(nvar));
+        let end = self.block()?;
+        if end.kind != TokenKind::End {
+            bail!(self.lexer, end, "`end`");
+        }
+
+        let jmp = self.jump_offset(istart as isize - self.fs.bytecodes.len() as isize - 1)?;
+        self.fs.bytecodes.push(ByteCode::Jump(jmp));
+
+        let jmp = self.jump_offset(self.fs.bytecodes.len() as isize - itest as isize - 1)?;
+        self.fs.bytecodes[itest] = ByteCode::Test(cond as u8, jmp);
+
+        self.close_breaks()?;
+        Ok(())
+    }
+
+    // stat ::= repeat block until exp
+    //
+    // start:
+    //     <block>
+    //     Test cond, start
+    fn repeat_stat(&mut self) -> Result<(), ParseError> {
+        let istart = self.fs.bytecodes.len();
+
+        // 条件表达式可以访问循环体内的局部变量
+        let nvar = self.fs.locals.len();
+        self.fs.break_blocks.push(BreakBlock::new(nvar));
+        let end = self.block_scope()?;
+        if end.kind != TokenKind::Until {
+            bail!(self.lexer, end, "`until`");
+        }
+
+        // 先求值条件再关闭上值，每次循环的局部变量都是新的
+        let cond = self.exp()?;
+        let cond = self.discharge_any(cond);
+        self.close_locals(nvar);
+        let jmp = self.jump_offset(istart as isize - self.fs.bytecodes.len() as isize - 1)?;
+        self.fs.bytecodes.push(ByteCode::Test(cond as u8, jmp));
+
+        self.close_breaks()?;
+        Ok(())
+    }
+
+    fn for_stat(&mut self) -> Result<(), ParseError> {
+        expect_next!(self.lexer, TokenKind::Name(var), "<variable>");
+
+        let token = self.lexer.next_token()?;
+        match token.kind {
+            TokenKind::Assign => self.numeric_for(var),
+            TokenKind::Comma | TokenKind::In => self.generic_for(token, var),
+            _ => bail!(self.lexer, token, "`=` or `in`"),
+        }
+    }
+
+    // stat ::= for Name `=` exp `,` exp [`,` exp] do block end
+    //
+    // 寄存器 A 至 A+2 存放循环的内部状态，A+3 为循环变量：
+    //     ForPrep A, end
+    // start:
+    //     <block>
+    //     ForLoop A, start
+    // end:
+    fn numeric_for(&mut self, var: SmolStr) -> Result<(), ParseError> {
+        let ibase = self.fs.sp;
+        let init = self.exp()?;
+        self.discharge(ibase, init);
+        expect_next!(self.lexer, TokenKind::Comma, "`,`");
+        let limit = self.exp()?;
+        self.discharge(ibase + 1, limit);
+
+        let token = self.lexer.next_token()?;
+        let step = match token.kind {
+            TokenKind::Comma => {
+                let step = self.exp()?;
+                expect_next!(self.lexer, TokenKind::Do, "`do`");
+                step
+            }
+            TokenKind::Do => ExpDesc::Integer(1),
+            _ => bail!(self.lexer, token, "`,` or `do`"),
+        };
+        self.discharge(ibase + 2, step);
+
+        let iprep = self.fs.bytecodes.len();
+        self.fs.bytecodes.push(ByteCode::ForPrep(ibase as u8, 0));
+
+        // 内部状态不能被访问，用非法的变量名占位
+        let nvar = self.fs.locals.len();
+        self.hidden_locals(3);
+        self.add_locals([var]);
+
+        self.fs.break_blocks.push(BreakBlock::new(nvar));
+        let end = self.block()?;
+        if end.kind != TokenKind::End {
+            bail!(self.lexer, end, "`end`");
+        }
+        // 每次循环的循环变量都是新的
+        self.close_locals(nvar);
+
+        let jmp = self.jump_offset(self.fs.bytecodes.len() as isize - iprep as isize)?;
+        self.fs.bytecodes.push(ByteCode::ForLoop(ibase as u8, jmp));
+        self.fs.bytecodes[iprep] = ByteCode::ForPrep(ibase as u8, jmp);
+
+        self.close_breaks()?;
+        Ok(())
+    }
+
+    // stat ::= for namelist in explist do block end
+    //
+    // 寄存器 A 至 A+3 依次存放迭代函数、状态、控制变量和待关闭变量，A+4 起为循环变量：
+    //     TForPrep A, call
+    // start:
+    //     <block>
+    // call:
+    //     TForCall A, <number of variables>
+    //     TForLoop A, start
+    fn generic_for(&mut self, mut token: Token, var: SmolStr) -> Result<(), ParseError> {
+        let mut vars = vec![var];
+        while token.kind == TokenKind::Comma {
+            expect_next!(self.lexer, TokenKind::Name(var), "<variable>");
+            vars.push(var);
+            token = self.lexer.next_token()?;
+        }
+        if token.kind != TokenKind::In {
+            bail!(self.lexer, token, "`,` or `in`");
+        }
+
+        let ibase = self.fs.sp;
+        self.explist_want(4)?;
+        expect_next!(self.lexer, TokenKind::Do, "`do`");
+
+        let iprep = self.fs.bytecodes.len();
+        self.fs.bytecodes.push(ByteCode::TForPrep(ibase as u8, 0));
+
+        let nvar = self.fs.locals.len();
+        self.hidden_locals(4);
+        let nname = vars.len();
+        self.add_locals(vars);
+
+        self.fs.break_blocks.push(BreakBlock::new(nvar));
+        let end = self.block()?;
+        if end.kind != TokenKind::End {
+            bail!(self.lexer, end, "`end`");
+        }
+        self.close_locals(nvar);
+
+        let icall = self.fs.bytecodes.len();
+        let jmp = self.jump_offset(icall as isize - iprep as isize - 1)?;
+        self.fs.bytecodes[iprep] = ByteCode::TForPrep(ibase as u8, jmp);
+        self.fs
+            .bytecodes
+            .push(ByteCode::TForCall(ibase as u8, nname as u8));
+        let jmp = self.jump_offset(icall as isize + 1 - iprep as isize)?;
+        self.fs.bytecodes.push(ByteCode::TForLoop(ibase as u8, jmp));
+
+        self.close_breaks()?;
+        Ok(())
+    }
+
+    fn break_stat(&mut self, token: Token) -> Result<(), ParseError> {
+        let Some(breaks) = self.fs.break_blocks.last_mut() else {
+            return Err(
+                SyntaxError::new(self.lexer.location(&token.span), "break outside a loop").into(),
+            );
+        };
+
+        breaks.jumps.push(self.fs.bytecodes.len());
+        self.fs.bytecodes.push(ByteCode::Jump(0));
+        Ok(())
+    }
+
+    /// Make the `break`s of the innermost loop jump to the end of it.
+    fn close_breaks(&mut self) -> Result<(), ParseError> {
+        let block = self.fs.break_blocks.pop().unwrap();
+        let iend = self.fs.bytecodes.len();
+        // `break`跳过了块结束处的 Close，需要在循环结束处补上
+        if block.captured && !block.jumps.is_empty() {
+            self.fs.bytecodes.push(ByteCode::Close(block.nvar as u8));
+        }
+        for i in block.jumps {
+            self.fs.bytecodes[i] =
+                ByteCode::Jump(self.jump_offset(iend as isize - i as isize - 1)?);
+        }
+        Ok(())
+    }
+
+    /// Declare `n` locals for the internal state of a loop,
+    /// whose names are illegal so they cannot be accessed.
+    fn hidden_locals(&mut self, n: usize) {
+        self.add_locals(iter::repeat_n(SmolStr::new_inline(HIDDEN_LOCAL), n));
+    }
+
+    fn local_var(&self, name: &str) -> Option<usize> {
+        self.fs.locals.iter().rposition(|var| var.name == name)
+    }
+
+    /// Find `name` as an upvalue of the function at `level` of the nesting,
+    /// where the main chunk is at level 0. The upvalue is added to the function,
+    /// and those between, if it's a local of some enclosing function.
+    fn find_upvalue(&mut self, name: &str, level: usize) -> Option<usize> {
+        if let Some(idx) = self
+            .func_at(level)
+            .upvalues
+            .iter()
+            .position(|(n, _)| n == name)
+        {
+            return Some(idx);
+        }
+        // 主函数没有上值，没找到的是全局变量
+        if level == 0 {
+            return None;
+        }
+
+        let outer = self.func_at(level - 1);
+        let desc = if let Some(reg) = outer.locals.iter().rposition(|var| var.name == name) {
+            outer.capture(reg);
+            UpvalDesc::Local(reg)
+        } else {
+            UpvalDesc::Upvalue(self.find_upvalue(name, level - 1)?)
+        };
+
+        let upvalues = &mut self.func_at(level).upvalues;
+        upvalues.push((name.into(), desc));
+        Some(upvalues.len() - 1)
+    }
+
+    fn func_at(&mut self, level: usize) -> &mut FuncState {
+        if level == self.enclosing.len() {
+            &mut self.fs
+        } else {
+            &mut self.enclosing[level]
+        }
+    }
 
-            let code = match self.lexer.next()? {
-                Token::Nil => ByteCode::SetGlobalConst(gi, self.add_const(Value::Nil) as u8),
-                Token::True => {
-                    ByteCode::SetGlobalConst(gi, self.add_const(Value::Boolean(true)) as u8)
+    /// Parse `return` which must be the last statement of a block,
+    /// and return the token which ends the block.
+    //
+    // retstat ::= return [explist] [`;`]
+    fn return_stat(&mut self) -> Result<Token, ParseError> {
+        let ibase = self.fs.sp;
+        let token = self.lexer.peek()?;
+        let code = if is_block_end(&token.kind) || token.kind == TokenKind::SemiColon {
+            ByteCode::Return(ibase as u8, 1)
+        } else {
+            let (n, last) = self.explist()?;
+            match last {
+                // 直接返回局部变量，无需复制
+                ExpDesc::Local(reg) if n == 1 => ByteCode::Return(reg as u8, 2),
+                last => {
+                    if self.discharge_expand(ibase + n - 1, last) {
+                        ByteCode::Return(ibase as u8, 0)
+                    } else {
+                        ByteCode::Return(ibase as u8, n as u8 + 1)
+                    }
+                }
+            }
+        };
+        self.fs.bytecodes.push(code);
+
+        if self.lexer.peek()?.kind == TokenKind::SemiColon {
+            self.lexer.next_token()?;
+        }
+        let end = self.lexer.next_token()?;
+        if !is_block_end(&end.kind) {
+            bail!(self.lexer, end);
+        }
+        Ok(end)
+    }
+
+    // stat ::= Name args
+    /// Parse a statement starting with a prefix expression,
+    /// where `token` is the first token of it.
+    //
+    // stat ::= varlist `=` explist | functioncall
+    fn exp_stat(&mut self, token: Token) -> Result<(), ParseError> {
+        let is_name = matches!(token.kind, TokenKind::Name(_));
+        let desc = self.suffixed_exp(token)?;
+        if matches!(
+            self.lexer.peek()?.kind,
+            TokenKind::Assign | TokenKind::Comma
+        ) {
+            if !is_assignable(&desc, is_name) {
+                bail!(self.lexer, self.lexer.next_token()?);
+            }
+            return self.assign(desc);
+        }
+
+        match desc {
+            // 丢弃所有返回值
+            ExpDesc::Call(ifunc, narg_plus) => {
+                self.fs
+                    .bytecodes
+                    .push(ByteCode::Call(ifunc as u8, narg_plus as u8, 1));
+                Ok(())
+            }
+            _ => bail!(self.lexer, self.lexer.next_token()?, "`=`"),
+        }
+    }
+
+    /// Parse a primary expression followed by any number of calls,
+    /// where `token` is the first token of it.
+    //
+    // suffixedexp ::= primaryexp {`.` Name | `[` exp `]` | `:` Name args | args}
+    fn suffixed_exp(&mut self, token: Token) -> Result<ExpDesc, ParseError> {
+        let mut desc = self.primary_exp(token)?;
+        loop {
+            let token = self.lexer.peek()?;
+            match token.kind {
+                TokenKind::ParL | TokenKind::String(_) | TokenKind::CurlyL => {
+                    // 函数放在第一个空闲寄存器，其后是参数，所以先释放求值用的临时寄存器
+                    self.free_operands(&desc);
+                    let ifunc = self.fs.sp;
+                    self.discharge(ifunc, desc);
+                    let token = self.lexer.next_token()?;
+                    desc = self.args(ifunc, token)?;
                 }
-                Token::False => {
-                    ByteCode::SetGlobalConst(gi, self.add_const(Value::Boolean(false)) as u8)
+                TokenKind::Dot => {
+                    self.lexer.next_token()?;
+                    expect_next!(self.lexer, TokenKind::Name(name), "<name>");
+                    let itable = self.discharge_any(desc);
+                    desc = self.field(itable, name);
                 }
-                Token::Integer(i) => {
-                    ByteCode::SetGlobalConst(gi, self.add_const(Value::Integer(i)) as u8)
+                TokenKind::SqurL => {
+                    self.lexer.next_token()?;
+                    let itable = self.discharge_any(desc);
+                    let key = self.exp()?;
+                    expect_next!(self.lexer, TokenKind::SqurR, "`]`");
+                    desc = self.index(itable, key);
                 }
-                Token::Float(f) => {
-                    ByteCode::SetGlobalConst(gi, self.add_const(Value::Float(f)) as u8)
+                // 方法调用`obj:m(args)`，即`obj.m(obj, args)`
+                TokenKind::Colon => {
+                    self.lexer.next_token()?;
+                    expect_next!(self.lexer, TokenKind::Name(name), "<name>");
+                    let iobj = self.discharge_any(desc);
+                    self.free_reg(iobj);
+                    let ifunc = self.fs.sp;
+                    // 先复制对象，因为它可能就位于函数的寄存器
+                    self.discharge(ifunc + 1, ExpDesc::Local(iobj));
+                    let method = self.field(iobj, name);
+                    self.discharge(ifunc, method);
+                    self.fs.sp = ifunc + 2;
+
+                    let token = self.lexer.next_token()?;
+                    desc = self.args(ifunc, token)?;
                 }
-                Token::String(s) => {
-                    ByteCode::SetGlobalConst(gi, self.add_const(Value::String(s.into())) as u8)
+                _ => return Ok(desc),
+            }
+        }
+    }
+
+    // primaryexp ::= Name | `(` exp `)`
+    fn primary_exp(&mut self, token: Token) -> Result<ExpDesc, ParseError> {
+        let desc = match token.kind {
+            TokenKind::Name(name) => self.var(name),
+            TokenKind::ParL => {
+                let desc = self.exp()?;
+                expect_next!(self.lexer, TokenKind::ParR, "`)`");
+                // 括号把多个值调整为一个
+                match desc {
+                    ExpDesc::Call(..) | ExpDesc::VarArgs => {
+                        ExpDesc::Local(self.discharge_any(desc))
+                    }
+                    desc => desc,
                 }
-                Token::Name(var) => {
-                    if let Some(src) = self.local_var(&var) {
-                        ByteCode::SetGlobalLocal(gi, src as u8)
+            }
+            _ => bail!(self.lexer, token, "<expression>"),
+        };
+
+        Ok(desc)
+    }
+
+    /// Parse the arguments of a call to the function in register `ifunc`,
+    /// where `token` is the first token of them.
+    //
+    // args ::= `(` [explist] `)` | tableconstructor | LiteralString
+    fn args(&mut self, ifunc: usize, token: Token) -> Result<ExpDesc, ParseError> {
+        // 方法调用的`self`已经位于第一个参数的位置
+        let iarg = self.fs.sp;
+        let narg_plus = match token.kind {
+            TokenKind::ParL => {
+                if self.lexer.peek()?.kind == TokenKind::ParR {
+                    self.lexer.next_token()?;
+                    iarg - ifunc
+                } else {
+                    let (n, last) = self.explist()?;
+                    expect_next!(self.lexer, TokenKind::ParR, "`)`");
+                    // 最后一个参数展开为所有返回值时，参数个数不定
+                    if self.discharge_expand(iarg + n - 1, last) {
+                        0
                     } else {
-                        ByteCode::SetGlobalGlobal(gi, self.add_const(Value::Identifier(var)) as u8)
+                        iarg + n - ifunc
                     }
                 }
-                t => bail!(t, "<expression>"),
-            };
+            }
+            TokenKind::String(s) => {
+                self.discharge(iarg, ExpDesc::String(s.into()));
+                iarg + 1 - ifunc
+            }
+            TokenKind::CurlyL => {
+                self.table_constructor()?;
+                iarg + 1 - ifunc
+            }
+            _ => bail!(self.lexer, token, "function arguments"),
+        };
 
-            Ok(code)
+        Ok(ExpDesc::Call(ifunc, narg_plus))
+    }
+
+    /// Parse a non-empty expression list, putting all but the last expression
+    /// into the registers from the first free one.
+    /// Return the number of expressions and the last one, which is left
+    /// for the caller to adjust.
+    //
+    // explist ::= exp {`,` exp}
+    fn explist(&mut self) -> Result<(usize, ExpDesc), ParseError> {
+        let ibase = self.fs.sp;
+        let mut n = 0;
+        loop {
+            // 每个表达式都从它的目标寄存器开始使用临时寄存器，
+            // 使得函数调用的返回值恰好位于目标寄存器
+            self.fs.sp = ibase + n;
+            let desc = self.exp()?;
+            n += 1;
+
+            if self.lexer.peek()?.kind != TokenKind::Comma {
+                return Ok((n, desc));
+            }
+            self.lexer.next_token()?;
+            self.discharge(ibase + n - 1, desc);
         }
     }
 
-    fn local_var(&self, name: &str) -> Option<usize> {
-        self.locals.iter().rposition(|var| var == name)
+    /// Parse a non-empty expression list and put `want` values into the registers
+    /// from the first free one, where missing values are filled with nil
+    /// and extra ones are evaluated and dropped.
+    fn explist_want(&mut self, want: usize) -> Result<(), ParseError> {
+        let ibase = self.fs.sp;
+        let (n, last) = self.explist()?;
+        self.explist_adjust(ibase, n, last, want);
+        Ok(())
+    }
+
+    /// Adjust the `n` values of an expression list parsed by [`Self::explist`]
+    /// from register `ibase` to `want`, see [`Self::explist_want`].
+    fn explist_adjust(&mut self, ibase: usize, n: usize, last: ExpDesc, want: usize) {
+        let ilast = ibase + n - 1;
+
+        match last {
+            // 函数调用和变长参数补足缺少的值
+            ExpDesc::Call(ifunc, narg_plus) if n <= want => {
+                let want_plus = want - n + 2;
+                self.fs.bytecodes.push(ByteCode::Call(
+                    ifunc as u8,
+                    narg_plus as u8,
+                    want_plus as u8,
+                ));
+            }
+            ExpDesc::VarArgs if n <= want => {
+                let want_plus = want - n + 2;
+                self.fs
+                    .bytecodes
+                    .push(ByteCode::VarArgs(ilast as u8, want_plus as u8));
+            }
+            last => {
+                self.discharge(ilast, last);
+                for i in n..want {
+                    self.discharge(ibase + i, ExpDesc::Nil);
+                }
+            }
+        }
+
+        self.fs.sp = ibase + want;
+    }
+
+    fn exp(&mut self) -> Result<ExpDesc, ParseError> {
+        self.exp_limit(0)
+    }
+
+    /// Parse an expression by precedence climbing,
+    /// consuming binary operators whose left priority is greater than `limit`.
+    //
+    // exp ::= (simpleexp | unop exp) {binop exp}
+    fn exp_limit(&mut self, limit: u8) -> Result<ExpDesc, ParseError> {
+        let mut desc = match self.lexer.peek()?.kind {
+            TokenKind::Sub | TokenKind::BitXor | TokenKind::Len | TokenKind::Not => {
+                let op = self.lexer.next_token()?.kind;
+                let operand = self.exp_limit(UNARY_PRI)?;
+                self.unop(op, operand)
+            }
+            _ => self.exp_simple()?,
+        };
+
+        loop {
+            let (left, right) = binop_pri(&self.lexer.peek()?.kind);
+            if left <= limit {
+                return Ok(desc);
+            }
+
+            let op = self.lexer.next_token()?.kind;
+            if matches!(op, TokenKind::And | TokenKind::Or) {
+                desc = self.logical_op(op, desc, right)?;
+                continue;
+            }
+
+            desc = self.preprocess_binop_left(desc);
+            let right_desc = self.exp_limit(right)?;
+            desc = self.binop(op, desc, right_desc);
+        }
+    }
+
+    /// Parse the right operand of `and`/`or`, which is evaluated
+    /// only if the left one doesn't decide the result.
+    //
+    // 结果存放在左操作数所在的寄存器中：
+    //     R[dst] := left
+    //     TestAndJump/TestOrJump dst, label
+    //     R[dst] := right
+    // label:
+    fn logical_op(
+        &mut self,
+        op: TokenKind,
+        left: ExpDesc,
+        limit: u8,
+    ) -> Result<ExpDesc, ParseError> {
+        let dst = match left {
+            // 不能覆盖局部变量
+            ExpDesc::Local(reg) if reg < self.fs.locals.len() => {
+                let dst = self.fs.sp;
+                self.discharge(dst, left);
+                dst
+            }
+            left => self.discharge_any(left),
+        };
+
+        let itest = self.fs.bytecodes.len();
+        self.fs.bytecodes.push(ByteCode::TestAndJump(0, 0));
+
+        let right = self.exp_limit(limit)?;
+        self.discharge(dst, right);
+        // 右操作数的临时值已经不再需要
+        self.fs.sp = dst + 1;
+
+        let jmp = self.jump_offset(self.fs.bytecodes.len() as isize - itest as isize - 1)?;
+        self.fs.bytecodes[itest] = match op {
+            TokenKind::And => ByteCode::TestAndJump(dst as u8, jmp),
+            _ => ByteCode::TestOrJump(dst as u8, jmp),
+        };
+
+        Ok(ExpDesc::Local(dst))
+    }
+
+    // simpleexp ::= nil | false | true | Numeral | LiteralString | `...` |
+    //               functiondef | tableconstructor | suffixedexp
+    fn exp_simple(&mut self) -> Result<ExpDesc, ParseError> {
+        let token = self.lexer.next_token()?;
+        let desc = match token.kind {
+            TokenKind::Nil => ExpDesc::Nil,
+            TokenKind::True => ExpDesc::Boolean(true),
+            TokenKind::False => ExpDesc::Boolean(false),
+            TokenKind::Integer(i) => ExpDesc::Integer(i),
+            TokenKind::Float(f) => ExpDesc::Float(f),
+            TokenKind::String(s) => ExpDesc::String(s.into()),
+            TokenKind::Name(_) | TokenKind::ParL => self.suffixed_exp(token)?,
+            TokenKind::Dots => {
+                if !self.fs.is_vararg {
+                    let location = self.lexer.location(&token.span);
+                    return Err(SyntaxError::new(
+                        location,
+                        "cannot use '...' outside a vararg function",
+                    )
+                    .into());
+                }
+                ExpDesc::VarArgs
+            }
+            TokenKind::Function => self.func_body(false)?,
+            TokenKind::CurlyL => self.table_constructor()?,
+            _ => bail!(self.lexer, token, "<expression>"),
+        };
+
+        Ok(desc)
+    }
+
+    /// Parse a table constructor after `{` into the first free register.
+    //
+    // tableconstructor ::= `{` [fieldlist] `}`
+    // fieldlist ::= field {fieldsep field} [fieldsep]
+    // field ::= `[` exp `]` `=` exp | Name `=` exp | exp
+    // fieldsep ::= `,` | `;`
+    //
+    //     NewTable t, <number of array items>, <number of hash items>
+    //     <value of array items>
+    //     SetField/SetTable t, key, value     每个哈希项
+    //     SetList t, n, offset                每 50 个数组项一次
+    fn table_constructor(&mut self) -> Result<ExpDesc, ParseError> {
+        let itable = self.fs.sp;
+        let inew = self.fs.bytecodes.len();
+        self.fs
+            .bytecodes
+            .push(ByteCode::NewTable(itable as u8, 0, 0));
+        self.fs.sp = itable + 1;
+
+        let mut narray = 0;
+        let mut nhash = 0;
+        // 已求值但尚未存入表的数组项个数
+        let mut npending = 0;
+        // 最后一个数组项可能展开为多个值，所以等到下一项时再求值
+        let mut last_item = None;
+
+        loop {
+            if self.lexer.peek()?.kind == TokenKind::CurlyR {
+                self.lexer.next_token()?;
+                break;
+            }
+
+            if let Some(desc) = last_item.take() {
+                self.discharge(itable + 1 + npending, desc);
+                npending += 1;
+                if npending == FIELDS_PER_FLUSH {
+                    self.flush_array_items(itable, npending, narray - npending);
+                    npending = 0;
+                }
+            }
+            self.fs.sp = itable + 1 + npending;
+
+            let is_field = matches!(self.lexer.peek()?.kind, TokenKind::Name(_))
+                && self.lexer.peek_nth(1)?.kind == TokenKind::Assign;
+            match self.lexer.peek()?.kind {
+                TokenKind::SqurL => {
+                    self.lexer.next_token()?;
+                    let key = self.exp()?;
+                    let key = self.discharge_any(key);
+                    expect_next!(self.lexer, TokenKind::SqurR, "`]`");
+                    expect_next!(self.lexer, TokenKind::Assign, "`=`");
+                    let value = self.exp()?;
+                    let value = self.discharge_any(value);
+                    self.fs.bytecodes.push(ByteCode::SetTable(
+                        itable as u8,
+                        key as u8,
+                        value as u8,
+                    ));
+                    nhash += 1;
+                }
+                _ if is_field => {
+                    expect_next!(self.lexer, TokenKind::Name(name), "<name>");
+                    self.lexer.next_token()?;
+                    let field = self.field(itable, name);
+                    let value = self.exp()?;
+                    self.assign_var(field, value);
+                    nhash += 1;
+                }
+                _ => {
+                    last_item = Some(self.exp()?);
+                    narray += 1;
+                }
+            }
+
+            let token = self.lexer.next_token()?;
+            match token.kind {
+                TokenKind::Comma | TokenKind::SemiColon => {}
+                TokenKind::CurlyR => break,
+                _ => bail!(self.lexer, token, "`}`"),
+            }
+        }
+
+        if let Some(desc) = last_item {
+            if self.discharge_expand(itable + 1 + npending, desc) {
+                // 展开的值个数不定，SetList 取到栈顶
+                narray -= 1;
+                self.fs.bytecodes.push(ByteCode::SetList(
+                    itable as u8,
+                    0,
+                    (narray - npending) as u16,
+                ));
+                npending = 0;
+            } else {
+                npending += 1;
+            }
+        }
+        if npending > 0 {
+            self.flush_array_items(itable, npending, narray - npending);
+        }
+
+        // 预先分配数组部分和哈希部分的空间
+        self.fs.bytecodes[inew] = ByteCode::NewTable(
+            itable as u8,
+            narray.min(u8::MAX as usize) as u8,
+            nhash.min(u8::MAX as usize) as u8,
+        );
+        self.fs.sp = itable + 1;
+        Ok(ExpDesc::Local(itable))
+    }
+
+    /// Store the `n` array items above the table in register `itable` into it,
+    /// after the first `offset` items.
+    fn flush_array_items(&mut self, itable: usize, n: usize, offset: usize) {
+        self.fs
+            .bytecodes
+            .push(ByteCode::SetList(itable as u8, n as u8, offset as u16));
     }
 
-    fn call_function(&mut self, token: Token, name: SmolStr) -> Result<ByteCode, ParseError> {
-        let ifunc = self.locals.len() as u8;
-        let iarg = ifunc + 1;
+    fn var(&mut self, name: SmolStr) -> ExpDesc {
+        // 优先查找后定义的变量，即作用域遮蔽
+        if let Some(reg) = self.local_var(&name) {
+            ExpDesc::Local(reg)
+        } else if let Some(idx) = self.find_upvalue(&name, self.enclosing.len()) {
+            ExpDesc::Upvalue(idx)
+        } else {
+            ExpDesc::Global(self.add_const(Value::Identifier(name)))
+        }
+    }
 
-        let code = self.load_var(ifunc, name);
-        self.bytecodes.push(code);
+    /// Index the table in register `itable` with the field `name`.
+    fn field(&mut self, itable: usize, name: SmolStr) -> ExpDesc {
+        self.index(itable, ExpDesc::String(name.as_bytes().into()))
+    }
+
+    /// Index the table in register `itable` with `key`,
+    /// where a string constant or a small integer is used without a register.
+    fn index(&mut self, itable: usize, key: ExpDesc) -> ExpDesc {
+        match key {
+            ExpDesc::String(s) => {
+                let key = self.add_const(Value::String(s.clone()));
+                if u8::try_from(key).is_ok() {
+                    ExpDesc::IndexField(itable, key)
+                } else {
+                    // 常量索引超出 GetField/SetField 操作数的范围，改为把键放入寄存器
+                    ExpDesc::Index(itable, self.discharge_any(ExpDesc::String(s)))
+                }
+            }
+            ExpDesc::Integer(i) if u8::try_from(i).is_ok() => ExpDesc::IndexInt(itable, i as u8),
+            key => ExpDesc::Index(itable, self.discharge_any(key)),
+        }
+    }
 
-        match token {
-            Token::ParL => {
-                let code = self.load_exp(iarg)?;
-                self.bytecodes.push(code);
-                expect_next!(self.lexer, Token::ParR, "`)`");
+    fn unop(&mut self, op: TokenKind, operand: ExpDesc) -> ExpDesc {
+        match (op, operand) {
+            // 常量折叠，使`-1`依然可以用 LoadInt 加载
+            (TokenKind::Sub, ExpDesc::Integer(i)) => ExpDesc::Integer(i.wrapping_neg()),
+            (TokenKind::Sub, ExpDesc::Float(f)) => ExpDesc::Float(-f),
+            (TokenKind::BitXor, ExpDesc::Integer(i)) => ExpDesc::Integer(!i),
+            (TokenKind::Not, ExpDesc::Nil | ExpDesc::Boolean(false)) => ExpDesc::Boolean(true),
+            (
+                TokenKind::Not,
+                ExpDesc::Boolean(true)
+                | ExpDesc::Integer(_)
+                | ExpDesc::Float(_)
+                | ExpDesc::String(_),
+            ) => ExpDesc::Boolean(false),
+            (TokenKind::Sub, operand) => {
+                ExpDesc::UnaryOp(ByteCode::Unm, self.discharge_any(operand))
+            }
+            (TokenKind::BitXor, operand) => {
+                ExpDesc::UnaryOp(ByteCode::BitNot, self.discharge_any(operand))
             }
-            Token::String(s) => {
-                let code = self.load_const(iarg, Value::String(s.into()));
-                self.bytecodes.push(code);
+            (TokenKind::Len, operand) => {
+                ExpDesc::UnaryOp(ByteCode::Len, self.discharge_any(operand))
             }
-            t => bail!(t, "`(<expression>)` or string"),
+            (TokenKind::Not, operand) => {
+                ExpDesc::UnaryOp(ByteCode::Not, self.discharge_any(operand))
+            }
+            _ => unreachable!(),
         }
+    }
+
+    /// Put the left operand into a register before the right one is parsed,
+    /// unless it is a constant or a local variable which cannot be changed
+    /// by the right operand.
+    fn preprocess_binop_left(&mut self, left: ExpDesc) -> ExpDesc {
+        match left {
+            ExpDesc::Nil
+            | ExpDesc::Boolean(_)
+            | ExpDesc::Integer(_)
+            | ExpDesc::Float(_)
+            | ExpDesc::String(_)
+            | ExpDesc::Local(_) => left,
+            left => ExpDesc::Local(self.discharge_any(left)),
+        }
+    }
+
+    fn binop(&mut self, op: TokenKind, left: ExpDesc, right: ExpDesc) -> ExpDesc {
+        let code = match op {
+            TokenKind::Add => ByteCode::Add,
+            TokenKind::Sub => ByteCode::Sub,
+            TokenKind::Mul => ByteCode::Mul,
+            TokenKind::Div => ByteCode::Div,
+            TokenKind::Idiv => ByteCode::Idiv,
+            TokenKind::Mod => ByteCode::Mod,
+            TokenKind::Pow => ByteCode::Pow,
+            TokenKind::BitAnd => ByteCode::BitAnd,
+            TokenKind::BitOr => ByteCode::BitOr,
+            TokenKind::BitXor => ByteCode::BitXor,
+            TokenKind::ShiftL => ByteCode::ShiftL,
+            TokenKind::ShiftR => ByteCode::ShiftR,
+            TokenKind::Concat => ByteCode::Concat,
+            TokenKind::Equal => ByteCode::Equal,
+            TokenKind::NotEq => ByteCode::NotEq,
+            TokenKind::Less | TokenKind::Greater => ByteCode::Less,
+            TokenKind::LesEq | TokenKind::GreEq => ByteCode::LesEq,
+            _ => unreachable!(),
+        };
+
+        // 先处理右操作数，使其临时寄存器得以复用
+        let right = self.discharge_any(right);
+        let left = self.discharge_any(left);
+        // `a > b`即`b < a`，操作数已在寄存器中，交换不影响求值顺序
+        if matches!(op, TokenKind::Greater | TokenKind::GreEq) {
+            ExpDesc::BinaryOp(code, right, left)
+        } else {
+            ExpDesc::BinaryOp(code, left, right)
+        }
+    }
 
-        Ok(ByteCode::Call(ifunc, 1))
+    /// Generate the bytecodes which put the value of `desc` into register `dst`.
+    fn discharge(&mut self, dst: usize, desc: ExpDesc) {
+        let dst_u8 = dst as u8;
+        let code = match desc {
+            ExpDesc::Nil => ByteCode::LoadNil(dst_u8),
+            ExpDesc::Boolean(b) => ByteCode::LoadBool(dst_u8, b),
+            ExpDesc::Integer(i) => {
+                if let Ok(i) = i16::try_from(i) {
+                    ByteCode::LoadInt(dst_u8, i)
+                } else {
+                    self.load_const(dst_u8, Value::Integer(i))
+                }
+            }
+            ExpDesc::Float(f) => self.load_const(dst_u8, Value::Float(f)),
+            ExpDesc::String(s) => self.load_const(dst_u8, Value::String(s)),
+            ExpDesc::Local(src) => ByteCode::Move(dst_u8, src as u8),
+            ExpDesc::Global(name) => ByteCode::GetGlobal(dst_u8, name as u16),
+            ExpDesc::Upvalue(idx) => ByteCode::GetUpval(dst_u8, idx as u8),
+            ExpDesc::Index(t, key) => ByteCode::GetTable(dst_u8, t as u8, key as u8),
+            ExpDesc::IndexField(t, key) => ByteCode::GetField(dst_u8, t as u8, key as u8),
+            ExpDesc::IndexInt(t, i) => ByteCode::GetI(dst_u8, t as u8, i),
+            ExpDesc::Function(idx) => ByteCode::Closure(dst_u8, idx as u16),
+            ExpDesc::UnaryOp(op, src) => op(dst_u8, src as u8),
+            ExpDesc::BinaryOp(op, left, right) => op(dst_u8, left as u8, right as u8),
+            ExpDesc::VarArgs => ByteCode::VarArgs(dst_u8, 2),
+            ExpDesc::Call(ifunc, narg_plus) => {
+                // 只保留第一个返回值，它位于函数所在的寄存器
+                self.fs
+                    .bytecodes
+                    .push(ByteCode::Call(ifunc as u8, narg_plus as u8, 2));
+                ByteCode::Move(dst_u8, ifunc as u8)
+            }
+        };
+
+        if !matches!(code, ByteCode::Move(dst, src) if dst == src) {
+            self.fs.bytecodes.push(code);
+        }
+        self.fs.sp = self.fs.sp.max(dst + 1);
+    }
+
+    /// Put the last expression of a list into register `dst`,
+    /// or expand it to all the values onwards if it is a function call or `...`,
+    /// and return whether it is expanded.
+    fn discharge_expand(&mut self, dst: usize, desc: ExpDesc) -> bool {
+        match desc {
+            ExpDesc::VarArgs => {
+                self.fs.bytecodes.push(ByteCode::VarArgs(dst as u8, 0));
+                true
+            }
+            ExpDesc::Call(ifunc, narg_plus) => {
+                self.fs
+                    .bytecodes
+                    .push(ByteCode::Call(ifunc as u8, narg_plus as u8, 0));
+                true
+            }
+            desc => {
+                self.discharge(dst, desc);
+                false
+            }
+        }
+    }
+
+    /// Put the value of `desc` into any register and return it.
+    /// A local variable is used in place, otherwise a free register is taken.
+    fn discharge_any(&mut self, desc: ExpDesc) -> usize {
+        match desc {
+            ExpDesc::Local(reg) => reg,
+            desc => {
+                // 操作数所在的临时寄存器可以用来存放结果
+                self.free_operands(&desc);
+                let dst = self.fs.sp;
+                self.discharge(dst, desc);
+                dst
+            }
+        }
+    }
+
+    /// Free the temporary registers holding the operands of `desc`,
+    /// which are no longer needed once it is discharged.
+    fn free_operands(&mut self, desc: &ExpDesc) {
+        match *desc {
+            ExpDesc::UnaryOp(_, src) => self.free_reg(src),
+            ExpDesc::BinaryOp(_, left, right) | ExpDesc::Index(left, right) => {
+                self.free_reg(right);
+                self.free_reg(left);
+            }
+            ExpDesc::IndexField(t, _) | ExpDesc::IndexInt(t, _) => self.free_reg(t),
+            // 参数所在的寄存器都可以复用，被调用的函数与返回值都位于 ifunc
+            ExpDesc::Call(ifunc, _) => self.fs.sp = ifunc,
+            _ => {}
+        }
+    }
+
+    /// Free `reg` if it is the last temporary register.
+    fn free_reg(&mut self, reg: usize) {
+        if reg >= self.fs.locals.len() && reg + 1 == self.fs.sp {
+            self.fs.sp -= 1;
+        }
     }
 }
 
-pub use self::error::{ParseError, UnexpectedTokenError};
+impl FuncState {
+    fn into_proto(mut self, nparam: usize) -> FuncProto {
+        tracing::debug!("constants: {:#?}", self.constants);
+        tracing::debug!("bytecode stack: [\n{}]", ByteCodeStack(&self.bytecodes));
+
+        self.end_locals(0);
+        let (upvalue_names, upvalues) = self.upvalues.into_iter().unzip();
+        FuncProto {
+            constants: self.constants,
+            bytecodes: self.bytecodes,
+            nparam,
+            is_vararg: self.is_vararg,
+            upvalues,
+            protos: self.protos,
+            upvalue_names,
+            locals: self.local_infos,
+        }
+    }
+
+    /// Put the locals from the `nvar`-th out of scope, recording their debug information.
+    fn end_locals(&mut self, nvar: usize) {
+        let end_pc = self.bytecodes.len();
+        for (reg, var) in self.locals.drain(nvar..).enumerate() {
+            if var.name != HIDDEN_LOCAL {
+                self.local_infos.push(LocalInfo {
+                    name: var.name,
+                    reg: nvar + reg,
+                    start_pc: var.start_pc,
+                    end_pc,
+                });
+            }
+        }
+    }
+
+    /// Mark the local in register `reg` as captured by an inner function.
+    fn capture(&mut self, reg: usize) {
+        self.locals[reg].captured = true;
+        for block in &mut self.break_blocks {
+            if block.nvar <= reg {
+                block.captured = true;
+            }
+        }
+    }
+}
+
+impl LocalVar {
+    fn new(name: SmolStr, start_pc: usize) -> Self {
+        Self {
+            name,
+            captured: false,
+            start_pc,
+        }
+    }
+}
+
+impl BreakBlock {
+    fn new(nvar: usize) -> Self {
+        Self {
+            nvar,
+            captured: false,
+            jumps: Vec::new(),
+        }
+    }
+}
+
+impl ExpDesc {
+    fn into_const(self) -> Result<Value, Self> {
+        match self {
+            Self::Nil => Ok(Value::Nil),
+            Self::Boolean(b) => Ok(Value::Boolean(b)),
+            Self::Integer(i) => Ok(Value::Integer(i)),
+            Self::Float(f) => Ok(Value::Float(f)),
+            Self::String(s) => Ok(Value::String(s)),
+            desc => Err(desc),
+        }
+    }
+}
+
+/// Whether `desc` can be assigned to. A variable in parentheses cannot,
+/// so the expression must start with a name unless it is a table field.
+fn is_assignable(desc: &ExpDesc, is_name: bool) -> bool {
+    match desc {
+        ExpDesc::Local(_) | ExpDesc::Upvalue(_) | ExpDesc::Global(_) => is_name,
+        // `(t).x`也可以赋值
+        ExpDesc::Index(..) | ExpDesc::IndexField(..) | ExpDesc::IndexInt(..) => true,
+        _ => false,
+    }
+}
+
+fn is_block_end(kind: &TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::End | TokenKind::Else | TokenKind::Elseif | TokenKind::Until | TokenKind::Eof
+    )
+}
+
+/// Name of the locals keeping the internal state of loops,
+/// which is not a valid name so they cannot be accessed.
+const HIDDEN_LOCAL: &str = "(for state)";
+
+/// Maximum number of constants, as well as inner functions, of a function.
+const MAX_CONSTANTS: usize = u16::MAX as usize + 1;
+
+/// Number of array items in registers before they are stored into the table,
+/// when a table constructor has many of them.
+const FIELDS_PER_FLUSH: usize = 50;
+
+/// Priority of unary operators, which is lower than `^` only.
+const UNARY_PRI: u8 = 12;
+
+/// Return the left and right priorities of a binary operator,
+/// where right associative ones have a lower right priority.
+/// The priorities of a non-operator token are zeros.
+fn binop_pri(op: &TokenKind) -> (u8, u8) {
+    match op {
+        TokenKind::Or => (1, 1),
+        TokenKind::And => (2, 2),
+        TokenKind::Equal
+        | TokenKind::NotEq
+        | TokenKind::Less
+        | TokenKind::LesEq
+        | TokenKind::Greater
+        | TokenKind::GreEq => (3, 3),
+        TokenKind::BitOr => (4, 4),
+        TokenKind::BitXor => (5, 5),
+        TokenKind::BitAnd => (6, 6),
+        TokenKind::ShiftL | TokenKind::ShiftR => (7, 7),
+        TokenKind::Concat => (9, 8),
+        TokenKind::Add | TokenKind::Sub => (10, 10),
+        TokenKind::Mul | TokenKind::Div | TokenKind::Idiv | TokenKind::Mod => (11, 11),
+        TokenKind::Pow => (14, 13),
+        _ => (0, 0),
+    }
+}
+
+pub use self::error::{ParseError, SyntaxError, UnexpectedTokenError};
 mod error {
-    use crate::{LexError, Token};
+    use crate::{LexError, Location, TokenKind};
 
     #[derive(Debug, thiserror::Error)]
     #[error("parse failed: {0}")]
     pub enum ParseError {
         Lex(#[from] LexError),
         Token(#[from] UnexpectedTokenError),
+        Syntax(#[from] SyntaxError),
+    }
+
+    /// A syntax error which is not about an unexpected token.
+    #[derive(Debug, thiserror::Error)]
+    #[error("{location}: {reason}")]
+    pub struct SyntaxError {
+        pub location: Location,
+        pub reason: &'static str,
+    }
+
+    impl SyntaxError {
+        pub(super) fn new(location: Location, reason: &'static str) -> Self {
+            Self { location, reason }
+        }
     }
 
     #[derive(Debug, thiserror::Error)]
     pub struct UnexpectedTokenError {
-        pub actual: Token,
+        pub location: Location,
+        pub actual: TokenKind,
         pub expected: &'static str,
     }
 
     impl std::fmt::Display for UnexpectedTokenError {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             if self.expected.is_empty() {
-                write!(f, "unexpected token {:?}", self.actual)
+                write!(f, "{}: unexpected token {:?}", self.location, self.actual)
             } else {
                 write!(
                     f,
-                    "expected token {} but got {:?}",
-                    self.expected, self.actual
+                    "{}: expected token {} but got {:?}",
+                    self.location, self.expected, self.actual
                 )
             }
         }
     }
 
     impl UnexpectedTokenError {
-        pub(super) fn new(actual: Token, expected: &'static str) -> Self {
-            Self { actual, expected }
+        pub(super) fn new(location: Location, actual: TokenKind, expected: &'static str) -> Self {
+            Self {
+                location,
+                actual,
+                expected,
+            }
         }
     }
 
     macro_rules! bail {
-        ($t:expr) => {
-            return Err(UnexpectedTokenError::new($t, "").into())
-        };
-        ($t:expr, $expected:literal) => {
-            return Err(UnexpectedTokenError::new($t, $expected).into())
+        ($lexer:expr, $t:expr) => {
+            bail!($lexer, $t, "")
         };
+        ($lexer:expr, $t:expr, $expected:literal) => {{
+            let t = $t;
+            return Err(
+                UnexpectedTokenError::new($lexer.location(&t.span), t.kind, $expected).into(),
+            );
+        }};
     }
     pub(super) use bail;
 
     macro_rules! expect_next {
         ($lexer:expr, $t:pat, $expected:literal) => {
-            let next_token = $lexer.next()?;
-            let $t = next_token else {
-                return Err(UnexpectedTokenError::new(next_token, $expected).into());
+            let token = $lexer.next_token()?;
+            let $t = token.kind else {
+                return Err(UnexpectedTokenError::new(
+                    $lexer.location(&token.span),
+                    token.kind,
+                    $expected,
+                )
+                .into());
             };
         };
     }
@@ -20,7 +20,7 @@ impl From<TinyVec<[u8; LossyStr::INLINE_CAP]>> for LossyStr {
     fn from(v: TinyVec<[u8; LossyStr::INLINE_CAP]>) -> Self {
         match v {
             TinyVec::Inline(v) => Self(Repr::Inline {
-                len: unsafe { mem::transmute(v.len() as u8) },
+                len: unsafe { mem::transmute::<u8, InlineSize>(v.len() as u8) },
                 buf: v.into_inner(),
             }),
             TinyVec::Heap(v) => Self(Repr::Heap(Rc::from(v))),
@@ -28,6 +28,12 @@ impl From<TinyVec<[u8; LossyStr::INLINE_CAP]>> for LossyStr {
     }
 }
 
+impl From<&[u8]> for LossyStr {
+    fn from(s: &[u8]) -> Self {
+        TinyVec::from(s).into()
+    }
+}
+
 impl LossyStr {
     pub const INLINE_CAP: usize = 23;
 
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+
+use crate::Value;
+
+/// A Lua table, whose integer keys from 1 on are kept in the array part
+/// and the other keys in the hash part.
+#[derive(Debug, Default)]
+pub struct Table {
+    array: Vec<Value>,
+    hash: HashPart,
+}
+
+/// The hash part of a table, which keeps its items in insertion order.
+///
+/// An item assigned nil stays as a dead item, so that `next` can still
+/// continue from its key, until the dead items are swept on growing.
+#[derive(Debug, Default)]
+struct HashPart {
+    items: Vec<(Value, Value)>,
+    /// 键在`items`中的位置
+    indices: HashMap<Value, usize>,
+    /// 值为 nil 的项数
+    ndead: usize,
+}
+
+impl Table {
+    /// Create a table with room for `narray` array items and `nhash` hash items.
+    pub fn new(narray: usize, nhash: usize) -> Self {
+        Self {
+            array: Vec::with_capacity(narray),
+            hash: HashPart::with_capacity(nhash),
+        }
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        let key = normalize(key);
+        if let Some(i) = self.array_index(&key) {
+            return self.array[i].clone();
+        }
+        self.hash.get(&key)
+    }
+
+    /// Assign `value` to `key`, where assigning nil removes the key.
+    pub fn set(&mut self, key: Value, value: Value) -> anyhow::Result<()> {
+        let key = match normalize(&key) {
+            Value::Nil => bail!("table index is nil"),
+            Value::Float(f) if f.is_nan() => bail!("table index is NaN"),
+            key => key,
+        };
+
+        if let Some(i) = self.array_index(&key) {
+            self.array[i] = value;
+            return Ok(());
+        }
+
+        match key {
+            // 紧接数组部分的键追加到数组部分，并把哈希部分中随后的键一并移过来
+            Value::Integer(i) if i > 0 && i as usize == self.array.len() + 1 => {
+                if matches!(value, Value::Nil) {
+                    return Ok(());
+                }
+                self.array.push(value);
+                loop {
+                    let key = Value::Integer(self.array.len() as i64 + 1);
+                    match self.hash.remove(&key) {
+                        Value::Nil => break,
+                        value => self.array.push(value),
+                    }
+                }
+            }
+            key => {
+                if matches!(value, Value::Nil) {
+                    self.hash.remove(&key);
+                } else {
+                    self.hash.insert(key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A border of the table, that is, an index `n` where `t[n]` is not nil
+    /// and `t[n + 1]` is nil, or 0 if `t[1]` is nil.
+    pub fn len(&self) -> usize {
+        let n = self.array.len();
+        if n == 0 || !matches!(self.array[n - 1], Value::Nil) {
+            // 哈希部分不会有紧接数组部分的键，因为它总会被移到数组部分
+            return n;
+        }
+
+        // 在数组部分二分查找边界，`array[lo - 1]`不为 nil（或`lo`为 0），`array[hi - 1]`为 nil
+        let (mut lo, mut hi) = (0, n);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if matches!(self.array[mid - 1], Value::Nil) {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+        lo
+    }
+
+    /// Return the item following `key` in a traversal of the table,
+    /// or the first one if `key` is nil, or `None` if `key` is the last one.
+    ///
+    /// Assigning to existing fields, including assigning nil,
+    /// does not disturb an ongoing traversal.
+    pub fn next(&self, key: &Value) -> anyhow::Result<Option<(Value, Value)>> {
+        // 先遍历数组部分，再遍历哈希部分
+        let key = normalize(key);
+        let start = match key {
+            Value::Nil => 0,
+            ref key => match self.array_index(key) {
+                Some(i) => i + 1,
+                None => match self.hash.indices.get(key) {
+                    Some(&i) => return Ok(self.hash.next(i + 1)),
+                    None => bail!("invalid key to 'next'"),
+                },
+            },
+        };
+
+        let item = self.array[start.min(self.array.len())..]
+            .iter()
+            .enumerate()
+            .find(|(_, v)| !matches!(v, Value::Nil))
+            .map(|(i, v)| (Value::Integer((start + i + 1) as i64), v.clone()));
+        Ok(item.or_else(|| self.hash.next(0)))
+    }
+
+    /// The index in the array part of `key`, if it is there.
+    fn array_index(&self, key: &Value) -> Option<usize> {
+        match *key {
+            Value::Integer(i) if i > 0 && (i as usize) <= self.array.len() => Some(i as usize - 1),
+            _ => None,
+        }
+    }
+}
+
+impl HashPart {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            items: Vec::with_capacity(capacity),
+            indices: HashMap::with_capacity(capacity),
+            ndead: 0,
+        }
+    }
+
+    fn get(&self, key: &Value) -> Value {
+        self.indices
+            .get(key)
+            .map(|&i| self.items[i].1.clone())
+            .unwrap_or_default()
+    }
+
+    fn insert(&mut self, key: Value, value: Value) {
+        if let Some(&i) = self.indices.get(&key) {
+            if matches!(self.items[i].1, Value::Nil) {
+                self.ndead -= 1;
+            }
+            self.items[i].1 = value;
+            return;
+        }
+
+        // 只有加入新键时才清理死项，遍历时加入新键的行为本就未定义
+        if self.ndead > 0 && self.items.len() == self.items.capacity() {
+            self.sweep();
+        }
+        self.indices.insert(key.clone(), self.items.len());
+        self.items.push((key, value));
+    }
+
+    /// Assign nil to `key` and return its old value.
+    fn remove(&mut self, key: &Value) -> Value {
+        match self.indices.get(key) {
+            Some(&i) => {
+                let old = std::mem::take(&mut self.items[i].1);
+                if !matches!(old, Value::Nil) {
+                    self.ndead += 1;
+                }
+                old
+            }
+            None => Value::Nil,
+        }
+    }
+
+    /// The first live item from position `start` on.
+    fn next(&self, start: usize) -> Option<(Value, Value)> {
+        self.items[start..]
+            .iter()
+            .find(|(_, v)| !matches!(v, Value::Nil))
+            .cloned()
+    }
+
+    /// Drop the dead items, keeping the order of the live ones.
+    fn sweep(&mut self) {
+        self.items.retain(|(_, v)| !matches!(v, Value::Nil));
+        self.indices.clear();
+        for (i, (key, _)) in self.items.iter().enumerate() {
+            self.indices.insert(key.clone(), i);
+        }
+        self.ndead = 0;
+    }
+}
+
+/// Convert a float key with an integral value to an integer,
+/// so that `t[1.0]` and `t[1]` are the same field.
+fn normalize(key: &Value) -> Value {
+    match *key {
+        Value::Float(f) if f.fract() == 0.0 && (-(2f64.powi(63))..2f64.powi(63)).contains(&f) => {
+            Value::Integer(f as i64)
+        }
+        ref key => key.clone(),
+    }
+}
@@ -1,8 +1,12 @@
 use indoc::indoc;
 use once_cell::sync::Lazy;
+use tinyvec::TinyVec;
 use tracing_subscriber::EnvFilter;
 
-use crate::rua;
+use crate::{
+    rua, rua_args, rua_bytes, ExeState, LexError, Lexer, Location, ParseProto, Span, TokenKind,
+    TriviaKind, Value,
+};
 
 static LOG: Lazy<()> = Lazy::new(|| {
     tracing_subscriber::fmt()
@@ -15,6 +19,14 @@ fn init_log() {
     Lazy::force(&LOG);
 }
 
+/// Run `source` and return the state for checking its globals.
+fn run(source: &str) -> ExeState {
+    let proto = ParseProto::new("test", source.as_bytes()).parse().unwrap();
+    let mut state = ExeState::new();
+    state.execute(proto, Vec::new()).unwrap();
+    state
+}
+
 #[test]
 fn test_print() {
     init_log();
@@ -75,3 +87,1548 @@ fn test_lossy_string() {
     "#};
     rua(source).unwrap();
 }
+
+#[test]
+fn test_long_string() {
+    init_log();
+    let source = indoc! {"
+        print [[hello, long string!]]
+        print [==[
+        ]] and ]=] are not the end]==]
+        --[[ a long
+        comment ]] print [=[inline]=]
+        --[==[
+        ]]
+        ]==]
+    "};
+    rua(source).unwrap();
+
+    let mut lexer = Lexer::new("test", b"[[\nline1\nline2]] [=[]]]=]");
+    assert_eq!(
+        lexer.next_token().unwrap().kind,
+        TokenKind::String(TinyVec::from(&b"line1\nline2"[..]))
+    );
+    assert_eq!(
+        lexer.next_token().unwrap().kind,
+        TokenKind::String(TinyVec::from(&b"]]"[..]))
+    );
+    assert_eq!(lexer.next_token().unwrap().kind, TokenKind::Eof);
+
+    let err = Lexer::new("test", b"[==[ never closed ]=]")
+        .next_token()
+        .unwrap_err();
+    assert!(err.to_string().contains("unfinished long string"));
+    let err = Lexer::new("test", b"--[[ never closed")
+        .next_token()
+        .unwrap_err();
+    assert!(err.to_string().contains("unfinished long comment"));
+}
+
+#[test]
+fn test_escape_sequence() {
+    init_log();
+    let source = indoc! {r#"
+        print 'single "quoted"'
+        print "double 'quoted'"
+        print 'skip \z
+               whitespace'
+        print "\u{4F60}\u{597D}"
+    "#};
+    rua(source).unwrap();
+
+    let lex_string = |s: &str| match Lexer::new("test", s.as_bytes()).next_token().unwrap().kind {
+        TokenKind::String(s) => s.to_vec(),
+        t => panic!("{t:?} is not a string"),
+    };
+    assert_eq!(lex_string(r"'it\'s'"), b"it's");
+    assert_eq!(lex_string("'a\\z  \n\t b'"), b"ab");
+    assert_eq!(lex_string("'a\\z\x0B\x0C\r\n b'"), b"ab");
+    assert_eq!(lex_string("'a\\\nb'"), b"a\nb");
+    assert_eq!(lex_string(r#""\x41\65\u{41}""#), b"AAA");
+    assert_eq!(
+        lex_string(r#""\u{7FF}\u{FFFF}""#),
+        "\u{7FF}\u{FFFF}".as_bytes()
+    );
+    assert_eq!(
+        lex_string(r#""\u{7FFFFFFF}""#),
+        [0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]
+    );
+
+    for (source, reason) in [
+        (r#""\256""#, "decimal escape too large"),
+        (r#""\x4""#, "hexadecimal digit expected"),
+        (r#""\u{80000000}""#, "UTF-8 value too large"),
+        (r#""\q""#, "invalid escape sequence"),
+        ("'no end", "unfinished string"),
+        ("'line\nbreak'", "unfinished string"),
+    ] {
+        let err = Lexer::new("test", source.as_bytes())
+            .next_token()
+            .unwrap_err();
+        assert!(err.to_string().contains(reason), "{source}: {err}");
+    }
+}
+
+#[test]
+fn test_number() {
+    init_log();
+    let source = indoc! {"
+        print(0xFF)
+        print(0x1p-4)
+        print(0xA.8p0)
+        print(9223372036854775808)
+        print(3e2)
+    "};
+    rua(source).unwrap();
+
+    let lex_number = |s: &str| Lexer::new("test", s.as_bytes()).next_token().unwrap().kind;
+    assert_eq!(lex_number("0xff"), TokenKind::Integer(255));
+    assert_eq!(lex_number("0XA"), TokenKind::Integer(10));
+    assert_eq!(lex_number("0xffffffffffffffff"), TokenKind::Integer(-1));
+    assert_eq!(lex_number("0x10000000000000001"), TokenKind::Integer(1));
+    assert_eq!(
+        lex_number("9223372036854775807"),
+        TokenKind::Integer(i64::MAX)
+    );
+    assert_eq!(
+        lex_number("9223372036854775808"),
+        TokenKind::Float(9223372036854775808.0)
+    );
+    assert_eq!(lex_number("0x1p-4"), TokenKind::Float(0.0625));
+    assert_eq!(lex_number("0xA.8p0"), TokenKind::Float(10.5));
+    assert_eq!(lex_number("0x.1"), TokenKind::Float(0.0625));
+    assert_eq!(lex_number("0x1P+2"), TokenKind::Float(4.0));
+    assert_eq!(lex_number("0x0p99999"), TokenKind::Float(0.0));
+    assert_eq!(lex_number("0x1p-1074"), TokenKind::Float(f64::from_bits(1)));
+    assert_eq!(
+        lex_number("0x10p-1078"),
+        TokenKind::Float(f64::from_bits(1))
+    );
+    assert_eq!(
+        lex_number("0x1000000000000000000000000000000p-1142"),
+        TokenKind::Float(f64::MIN_POSITIVE)
+    );
+    assert_eq!(lex_number("0x1p-1075"), TokenKind::Float(0.0));
+    assert_eq!(lex_number("0x1p1024"), TokenKind::Float(f64::INFINITY));
+    assert_eq!(lex_number("3."), TokenKind::Float(3.0));
+    assert_eq!(lex_number(".5"), TokenKind::Float(0.5));
+    assert_eq!(lex_number("5e-1"), TokenKind::Float(0.5));
+    assert_eq!(lex_number("1E2"), TokenKind::Float(100.0));
+
+    for source in ["0x", "3x", "1e", "0x1p", "1..2", "0xg", "1.2.3"] {
+        let err = Lexer::new("test", source.as_bytes())
+            .next_token()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("malformed number"),
+            "{source}: {err}"
+        );
+    }
+}
+
+#[test]
+fn test_token_span() {
+    init_log();
+    let mut lexer = Lexer::new("test", b"local s = [[\nab]]\n  print(s)");
+    let mut spans = Vec::new();
+    loop {
+        let token = lexer.next_token().unwrap();
+        spans.push(token.span);
+        if token.kind == TokenKind::Eof {
+            break;
+        }
+    }
+    let span = |start, end, line, col| Span {
+        start,
+        end,
+        line,
+        col,
+    };
+    assert_eq!(
+        spans,
+        [
+            span(0, 5, 1, 1),
+            span(6, 7, 1, 7),
+            span(8, 9, 1, 9),
+            span(10, 17, 1, 11),
+            span(20, 25, 3, 3),
+            span(25, 26, 3, 8),
+            span(26, 27, 3, 9),
+            span(27, 28, 3, 10),
+            span(28, 28, 3, 11),
+        ]
+    );
+
+    let source = "local a = 1\nlocal b = [==[\n";
+    let err = ParseProto::new("chunk", source.as_bytes())
+        .parse()
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: chunk:2:11: unfinished long string"
+    );
+    let err = ParseProto::new("chunk", b"local a = 1\n  local = 2")
+        .parse()
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: chunk:2:9: expected token <variable> but got Assign"
+    );
+}
+
+#[test]
+fn test_lex_error() {
+    init_log();
+    let lex_error = |s: &str| {
+        let mut lexer = Lexer::new("test", s.as_bytes());
+        loop {
+            match lexer.next_token() {
+                Ok(token) if token.kind == TokenKind::Eof => panic!("{s} is lexed"),
+                Ok(_) => {}
+                Err(e) => break e,
+            }
+        }
+    };
+    let location = |line, col| Location {
+        chunk: "test".into(),
+        line,
+        col,
+    };
+
+    assert!(matches!(
+        lex_error("a = 'abc\nb = 1"),
+        LexError::UnfinishedString(l) if l == location(1, 9)
+    ));
+    assert!(matches!(
+        lex_error("a = 1\nb = [[abc"),
+        LexError::UnfinishedLongString(l) if l == location(2, 5)
+    ));
+    assert!(matches!(
+        lex_error("--[==[ abc ]]"),
+        LexError::UnfinishedLongComment(l) if l == location(1, 3)
+    ));
+    assert!(matches!(
+        lex_error("x = 0x1.p"),
+        LexError::MalformedNumber(l) if l == location(1, 5)
+    ));
+    assert!(matches!(
+        lex_error("'\\q'"),
+        LexError::InvalidEscape { location: l, reason: "invalid escape sequence" } if l == location(1, 2)
+    ));
+    assert!(matches!(
+        lex_error("a = 1 $"),
+        LexError::UnexpectedChar { location: l, actual: b'$' } if l == location(1, 7)
+    ));
+    assert_eq!(
+        lex_error("a = 1 $").to_string(),
+        "test:1:7: unexpected symbol near '$'"
+    );
+}
+
+#[test]
+fn test_non_utf8_source() {
+    init_log();
+    let source = b"local s = 'caf\xE9'\nprint(s)\nprint [[\xFF\xFE]]\n";
+    rua_bytes("latin1", source).unwrap();
+
+    let proto = ParseProto::new("latin1", source).parse().unwrap();
+    let Value::String(s) = &proto.constants[0] else {
+        panic!("{:?} is not a string", proto.constants[0]);
+    };
+    assert_eq!(s.as_bytes(), b"caf\xE9");
+
+    let err = Lexer::new("latin1", b"\xE9 = 1").next_token().unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        r"latin1:1:1: unexpected symbol near '\xe9'"
+    );
+}
+
+#[test]
+fn test_lossless_lexer() {
+    init_log();
+    let source = indoc! {"
+        -- line comment
+        local a = 1  --[[ long
+        comment ]] print(a)
+
+    "};
+
+    let mut lexer = Lexer::lossless("test", source.as_bytes());
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token().unwrap();
+        let eof = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if eof {
+            break;
+        }
+    }
+
+    let mut rebuilt = Vec::new();
+    for token in &tokens {
+        for trivia in &token.trivia {
+            rebuilt.extend_from_slice(lexer.text(&trivia.span));
+        }
+        rebuilt.extend_from_slice(lexer.text(&token.span));
+    }
+    assert_eq!(rebuilt, source.as_bytes());
+
+    let kinds = |i: usize| tokens[i].trivia.iter().map(|t| t.kind).collect::<Vec<_>>();
+    assert_eq!(kinds(0), [TriviaKind::LineComment, TriviaKind::Whitespace]);
+    assert_eq!(lexer.text(&tokens[0].trivia[0].span), b"-- line comment");
+    assert_eq!(
+        kinds(4),
+        [
+            TriviaKind::Whitespace,
+            TriviaKind::LongComment,
+            TriviaKind::Whitespace
+        ]
+    );
+    assert_eq!(tokens.last().unwrap().trivia.len(), 1);
+
+    // 默认的词法分析器丢弃注释与空白
+    let token = Lexer::new("test", source.as_bytes()).next_token().unwrap();
+    assert_eq!(token.kind, TokenKind::Local);
+    assert!(token.trivia.is_empty());
+}
+
+#[test]
+fn test_line_endings() {
+    init_log();
+    rua("#!/usr/bin/env rua\nprint 'shebang'\n--").unwrap();
+    rua("--\r\nprint 'CRLF' -- comment\r\nprint 'CR'\r--[[\r\n]]print 'EOF' --").unwrap();
+
+    let mut lexer = Lexer::new("test", b"#!shebang\r\na\r\rb\n\rc\r\n[[\r\nx\r\ny\n\rz]]");
+    let mut next = || {
+        let token = lexer.next_token().unwrap();
+        (token.kind, token.span.line, token.span.col)
+    };
+    assert_eq!(next(), (TokenKind::Name("a".into()), 2, 1));
+    assert_eq!(next(), (TokenKind::Name("b".into()), 4, 1));
+    assert_eq!(next(), (TokenKind::Name("c".into()), 5, 1));
+    assert_eq!(
+        next(),
+        (TokenKind::String(TinyVec::from(&b"x\ny\nz"[..])), 6, 1)
+    );
+    assert_eq!(next(), (TokenKind::Eof, 9, 4));
+
+    let mut lexer = Lexer::lossless("test", b"#!/usr/bin/env rua\nx");
+    let token = lexer.next_token().unwrap();
+    assert_eq!(token.trivia[0].kind, TriviaKind::Shebang);
+    assert_eq!(lexer.text(&token.trivia[0].span), b"#!/usr/bin/env rua");
+    assert_eq!(
+        token.trivia[0].span,
+        Span {
+            start: 0,
+            end: 18,
+            line: 1,
+            col: 1,
+        }
+    );
+
+    let err = Lexer::new("test", b"\r\n\r\n  $").next_token().unwrap_err();
+    assert_eq!(err.to_string(), "test:3:3: unexpected symbol near '$'");
+}
+
+#[test]
+fn test_lexer_lookahead() {
+    init_log();
+    let mut lexer = Lexer::new("test", b"a.b = 1");
+    assert_eq!(lexer.peek().unwrap().kind, TokenKind::Name("a".into()));
+    assert_eq!(lexer.peek_nth(1).unwrap().kind, TokenKind::Dot);
+    assert_eq!(lexer.peek_nth(4).unwrap().kind, TokenKind::Integer(1));
+    assert_eq!(lexer.peek_nth(6).unwrap().kind, TokenKind::Eof);
+    assert_eq!(
+        lexer.next_token().unwrap().kind,
+        TokenKind::Name("a".into())
+    );
+    assert_eq!(lexer.peek().unwrap().kind, TokenKind::Dot);
+
+    let kinds = lexer.map(|t| t.unwrap().kind).collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            TokenKind::Dot,
+            TokenKind::Name("b".into()),
+            TokenKind::Assign,
+            TokenKind::Integer(1),
+            TokenKind::Eof,
+        ]
+    );
+
+    // 预读时的错误不影响之前的 token
+    let mut lexer = Lexer::new("test", b"x = $");
+    assert!(lexer.peek_nth(2).is_err());
+    let tokens = lexer.collect::<Vec<_>>();
+    assert_eq!(tokens.len(), 3);
+    assert!(matches!(tokens[2], Err(LexError::UnexpectedChar { .. })));
+}
+
+#[test]
+fn test_arithmetic() {
+    init_log();
+    let source = indoc! {r#"
+        local a = 10
+        prec = 1 + 2 * 3 - 4 / 2
+        paren = (1 + 2) * 3
+        sub = a-1
+        neg = - a ^ 2
+        pow = 2 ^ 3 ^ 2
+        cat = "a" .. "b" .. 1 .. 2.0
+        idiv = 7 // -2
+        fidiv = 7.5 // 2
+        mod = -7 % 3
+        fmod = 5.5 % -2
+        bits = 1 << 4 | 3 & ~1 ~ 8
+        shr = -1 >> 60
+        len = #"hello" + "10"
+        wrap = 0x7fffffffffffffff + 1
+        -- 0.0 与 -0.0 是不同的常量
+        local zero, neg_zero = 0.0, -0.0
+        zero_inv, neg_zero_inv = 1 / zero, 1 / neg_zero
+        print(prec)
+        print(a - -a)
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("prec"), Value::Float(5.0));
+    assert_eq!(state.global("paren"), Value::Integer(9));
+    assert_eq!(state.global("sub"), Value::Integer(9));
+    assert_eq!(state.global("neg"), Value::Float(-100.0));
+    assert_eq!(state.global("pow"), Value::Float(512.0));
+    assert_eq!(state.global("idiv"), Value::Integer(-4));
+    assert_eq!(state.global("fidiv"), Value::Float(3.0));
+    assert_eq!(state.global("mod"), Value::Integer(2));
+    assert_eq!(state.global("fmod"), Value::Float(-0.5));
+    assert_eq!(state.global("bits"), Value::Integer(16 | (3 & !1) ^ 8));
+    assert_eq!(state.global("shr"), Value::Integer(15));
+    assert_eq!(state.global("zero_inv"), Value::Float(f64::INFINITY));
+    assert_eq!(
+        state.global("neg_zero_inv"),
+        Value::Float(f64::NEG_INFINITY)
+    );
+    assert_eq!(state.global("len"), Value::Integer(15));
+    assert_eq!(state.global("wrap"), Value::Integer(i64::MIN));
+    assert_eq!(format!("{:?}", state.global("cat")), "ab12.0");
+
+    let kinds = Lexer::new("test", b"a-1")
+        .map(|t| t.unwrap().kind)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            TokenKind::Name("a".into()),
+            TokenKind::Sub,
+            TokenKind::Integer(1),
+            TokenKind::Eof,
+        ]
+    );
+
+    let err = rua("local x = 1 // 0").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to perform 'n//0'");
+    let err = rua("local x = 1 % 0").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to perform 'n%0'");
+    let err = rua("local x = 1.5 | 0").unwrap_err();
+    assert_eq!(err.to_string(), "number has no integer representation");
+    let err = rua("local x = {} + 1").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "attempt to perform arithmetic on a table value"
+    );
+    let err = rua("local x = nil .. 1").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to concatenate a nil value");
+}
+
+#[test]
+fn test_float_format() {
+    let cases = [
+        (1.0, "1.0"),
+        (-0.0, "-0.0"),
+        (0.1, "0.1"),
+        (1e15, "1e+15"),
+        (1e100, "1e+100"),
+        (123456789012345.0, "1.2345678901234e+14"),
+        (2f64.powi(53), "9.007199254741e+15"),
+        (1e-5, "1e-05"),
+        (0.0001, "0.0001"),
+        (std::f64::consts::PI, "3.1415926535898"),
+        (f64::INFINITY, "inf"),
+        (f64::NEG_INFINITY, "-inf"),
+    ];
+    for (f, s) in cases {
+        assert_eq!(format!("{:?}", Value::Float(f)), s);
+    }
+}
+
+#[test]
+fn test_logical_operators() {
+    init_log();
+    let source = indoc! {r#"
+        local a = 1
+        local b = nil
+        eq = 1 == 1.0
+        ne = "1" ~= 1
+        lt = 2^53 < 2^53 + 1
+        big = 9007199254740993 > 2^53
+        le = "a" <= "ab"
+        ge = "b" >= "abc"
+        gt = -1 > 1
+        nan = 0/0 == 0/0
+        and1 = a and b
+        and2 = b and 1 // 0
+        or1 = b or "default"
+        or2 = a or 1 // 0
+        mixed = b and 1 or 2
+        not1 = not b
+        not2 = not 0
+        not3 = not not a
+        cmp = 1 < 2 == true
+        print(a and "yes" or "no")
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("eq"), Value::Boolean(true));
+    assert_eq!(state.global("ne"), Value::Boolean(true));
+    assert_eq!(state.global("lt"), Value::Boolean(false));
+    assert_eq!(state.global("big"), Value::Boolean(true));
+    assert_eq!(state.global("le"), Value::Boolean(true));
+    assert_eq!(state.global("ge"), Value::Boolean(true));
+    assert_eq!(state.global("gt"), Value::Boolean(false));
+    assert_eq!(state.global("nan"), Value::Boolean(false));
+    assert_eq!(state.global("and1"), Value::Nil);
+    assert_eq!(state.global("and2"), Value::Nil);
+    assert_eq!(format!("{:?}", state.global("or1")), "default");
+    assert_eq!(state.global("or2"), Value::Integer(1));
+    assert_eq!(state.global("mixed"), Value::Integer(2));
+    assert_eq!(state.global("not1"), Value::Boolean(true));
+    assert_eq!(state.global("not2"), Value::Boolean(false));
+    assert_eq!(state.global("not3"), Value::Boolean(true));
+    assert_eq!(state.global("cmp"), Value::Boolean(true));
+
+    let err = rua("local x = 1 < '2'").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to compare number with string");
+    let err = rua("local x = nil < nil").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to compare two nil values");
+
+    // 右操作数过长时，跳转偏移超出字节码的表示范围
+    let source = format!(
+        "local x, y = false, 1 x = x and y{}\nprint(x)",
+        " + y".repeat(40000)
+    );
+    let err = rua(&source).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:2:1: control structure too long"
+    );
+}
+
+#[test]
+fn test_if() {
+    init_log();
+    let source = indoc! {r#"
+        local score = 85
+        if score >= 90 then
+            grade = "A"
+        elseif score >= 80 then
+            local bonus = 1
+            grade = "B"
+        elseif score >= 70 then
+            grade = "C"
+        else
+            grade = "F"
+        end
+        if nil then first = 1 else first = 2 end
+        if 0 then second = true end
+        if false then third = true end;
+        if 1 > 2 then
+            nested = 1
+        else
+            if 2 > 1 then nested = 2 end
+        end
+        local bonus = "outer"
+        print(grade)
+    "#};
+    let state = run(source);
+    assert_eq!(format!("{:?}", state.global("grade")), "B");
+    assert_eq!(state.global("first"), Value::Integer(2));
+    assert_eq!(state.global("second"), Value::Boolean(true));
+    assert_eq!(state.global("third"), Value::Nil);
+    assert_eq!(state.global("nested"), Value::Integer(2));
+
+    let err = rua("if true then print(1)").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:1:22: expected token `end` but got Eof"
+    );
+    let err = rua("print(1) end").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:1:10: expected token <eof> but got End"
+    );
+
+    // 跳转偏移超出字节码的表示范围
+    let source = format!(
+        "local x, y = 0, 0 if false then\n{}end print(x)",
+        "x = y\n".repeat(40000)
+    );
+    let err = rua(&source).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:40002:5: control structure too long"
+    );
+}
+
+#[test]
+fn test_loop() {
+    init_log();
+    let source = indoc! {r#"
+        local i = 0
+        sum = 0
+        while i < 10 do
+            i = i + 1
+            if i % 2 == 0 then
+                sum = sum + i
+            end
+        end
+
+        local n = 0
+        repeat
+            local next = n + 1
+            n = next
+        until next >= 5
+        repeated = n
+
+        local j = 0
+        while true do
+            j = j + 1
+            local k = 0
+            repeat
+                k = k + 1
+                if k == 3 then break end
+            until false
+            if j * k > 10 then break end
+        end
+        broken = j
+
+        once = 0
+        repeat once = once + 1 until true
+        print(sum)
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("sum"), Value::Integer(30));
+    assert_eq!(state.global("repeated"), Value::Integer(5));
+    assert_eq!(state.global("broken"), Value::Integer(4));
+    assert_eq!(state.global("once"), Value::Integer(1));
+
+    let err = rua("if true then break end").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:1:14: break outside a loop"
+    );
+
+    // 循环体过长时，跳转偏移超出字节码的表示范围
+    let body = "x = y\n".repeat(40000);
+    for (source, location) in [
+        (
+            format!("local x, y = 0, 0 while x > 0 do\n{body}end print(x)"),
+            "40002:5",
+        ),
+        (
+            format!("local x, y = 0, 0 repeat\n{body}until x print(x)"),
+            "40002:9",
+        ),
+    ] {
+        let err = rua(&source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("parse failed: main:{location}: control structure too long")
+        );
+    }
+}
+
+#[test]
+fn test_numeric_for() {
+    init_log();
+    let source = indoc! {r#"
+        sum = 0
+        for i = 1, 10 do
+            sum = sum + i
+            i = i * 100
+        end
+
+        down = ""
+        for i = 3, 1, -1 do down = down .. i end
+
+        floats = ""
+        for x = 1, 2, 0.5 do floats = floats .. x .. " " end
+
+        int_float_limit = 0
+        for i = 1, 3.9 do int_float_limit = i end
+
+        never = true
+        for i = 1, 0 do never = false end
+
+        edge = 0
+        for i = 0x7ffffffffffffffd, 0x7fffffffffffffff do edge = edge + 1 end
+        for i = -0x7fffffffffffffff - 1, -0x7fffffffffffffff - 1 + 2 do edge = edge + 1 end
+
+        huge = 0
+        for i = 1, 1e100 do
+            huge = huge + 1
+            if huge == 5 then break end
+        end
+
+        -- 与 NaN 比较总为假，循环不执行
+        nan_runs = 0
+        for i = 1.0, 0/0 do nan_runs = nan_runs + 1 end
+        for i = 0/0, 1 do nan_runs = nan_runs + 1 end
+        for i = 0/0, 1, -1 do nan_runs = nan_runs + 1 end
+        print(sum)
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("sum"), Value::Integer(55));
+    assert_eq!(format!("{:?}", state.global("down")), "321");
+    assert_eq!(format!("{:?}", state.global("floats")), "1.0 1.5 2.0 ");
+    assert_eq!(state.global("int_float_limit"), Value::Integer(3));
+    assert_eq!(state.global("never"), Value::Boolean(true));
+    assert_eq!(state.global("edge"), Value::Integer(6));
+    assert_eq!(state.global("huge"), Value::Integer(5));
+    assert_eq!(state.global("nan_runs"), Value::Integer(0));
+
+    let err = rua("for i = 1, 10, 0 do end").unwrap_err();
+    assert_eq!(err.to_string(), "'for' step is zero");
+    let err = rua("for i = 1.0, 10, 0 do end").unwrap_err();
+    assert_eq!(err.to_string(), "'for' step is zero");
+    let err = rua("for i = 1, nil do end").unwrap_err();
+    assert_eq!(err.to_string(), "'for' limit must be a number");
+
+    // 循环体过长时，跳转偏移超出字节码的表示范围
+    let source = format!(
+        "local x, y = 0, 0 for i = 1, 2 do\n{}end print(x)",
+        "x = y\n".repeat(70000)
+    );
+    let err = rua(&source).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:70002:5: control structure too long"
+    );
+}
+
+#[test]
+fn test_generic_for() {
+    init_log();
+    // `print`没有返回值，循环变量为 nil，循环体不会执行
+    let source = indoc! {r#"
+        count = 0
+        for line in print, "generic for" do
+            count = count + 1
+        end
+        for a, b, c in print, "extra", nil, nil, "values", 1 + 1 do
+            count = count + 1
+        end
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("count"), Value::Integer(0));
+
+    let err = rua("for k, v in nil do end").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to call a nil value");
+    let err = rua("for k in print, nil, nil, true do end").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "variable '(for state)' got a non-closable value"
+    );
+    let err = rua("for k v in print do end").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:1:7: expected token `=` or `in` but got Name(\"v\")"
+    );
+
+    // 循环体过长时，跳转偏移超出字节码的表示范围
+    let source = format!(
+        "local x, y = 0, 0 for k in pairs({{}}) do\n{}end print(x)",
+        "x = y\n".repeat(70000)
+    );
+    let err = rua(&source).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:70002:5: control structure too long"
+    );
+}
+
+#[test]
+fn test_do_block() {
+    init_log();
+    let source = indoc! {r#"
+        local x = "outer"
+        do
+            local x = "inner"
+            local y = x .. "!"
+            inner = y
+            do
+                local x = x .. " most"
+                innermost = x
+            end
+            after_innermost = x
+        end
+        outer = x
+        y_after = y
+
+        do
+            local print = nil
+        end
+        print "global print is visible again"
+
+        -- 块结束后寄存器被复用
+        local a = 1
+        do local b = 2 end
+        local c = 3
+        sum = a + c
+    "#};
+    let state = run(source);
+    assert_eq!(format!("{:?}", state.global("inner")), "inner!");
+    assert_eq!(format!("{:?}", state.global("innermost")), "inner most");
+    assert_eq!(format!("{:?}", state.global("after_innermost")), "inner");
+    assert_eq!(format!("{:?}", state.global("outer")), "outer");
+    assert_eq!(state.global("y_after"), Value::Nil);
+    assert_eq!(state.global("sum"), Value::Integer(4));
+
+    let proto = ParseProto::new("test", b"local a = 1 do local b = 2 end local c = 3")
+        .parse()
+        .unwrap();
+    assert_eq!(
+        format!("{:?}", proto.bytecodes),
+        "[LoadInt(0, 1), LoadInt(1, 2), LoadInt(1, 3)]"
+    );
+
+    let err = rua("do local x = 1").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:1:15: expected token `end` but got Eof"
+    );
+}
+
+#[test]
+fn test_function() {
+    init_log();
+    let source = indoc! {r#"
+        function greet(name)
+            print("hello, " .. name)
+            greeted = name
+        end
+        greet "function"
+
+        local function double(x)
+            local y = x * 2
+            doubled = y
+        end
+        double(21)
+
+        local square = function(x) squared = x * x end
+        square(5)
+
+        local function add(a, b)
+            sum = a + (b or 10)
+        end
+        add(1)
+
+        function outer(n)
+            function inner(m)
+                nested = m + 1
+            end
+            inner(n * 10)
+        end
+        outer(4)
+    "#};
+    let state = run(source);
+    assert_eq!(format!("{:?}", state.global("greeted")), "function");
+    assert_eq!(state.global("doubled"), Value::Integer(42));
+    assert_eq!(state.global("squared"), Value::Integer(25));
+    assert_eq!(state.global("sum"), Value::Integer(11));
+    assert_eq!(state.global("nested"), Value::Integer(41));
+
+    // 每个函数有各自的原型，嵌套在外层函数中
+    let proto = ParseProto::new("test", b"function f(a, b) local c = 'inner' end")
+        .parse()
+        .unwrap();
+    let f = &proto.protos[0];
+    assert_eq!(f.nparam, 2);
+    assert_eq!(format!("{:?}", f.constants), "[inner]");
+
+    let err = rua("function t.f() end").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to index a nil value (global 't')");
+    let err = rua("local x = 1 x(2)").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to call a number value");
+}
+
+#[test]
+fn test_closure() {
+    init_log();
+    let source = indoc! {r#"
+        -- 兄弟闭包共享同一个局部变量
+        local function counter(start)
+            local n = start
+            function inc(step) n = n + step end
+            function get(_) count = n end
+        end
+        counter(10)
+        inc(1)
+        inc(2)
+        get(0)
+
+        -- 离开作用域后，上值保存变量最后的值
+        do
+            local x = 1
+            function set_x(v) x = v end
+            function get_x(_) got = x end
+            x = 2
+        end
+        get_x(0)
+        before = got
+        set_x(3)
+        get_x(0)
+
+        -- 每次循环的局部变量都是新的
+        for i = 1, 3 do
+            local j = i * 10
+            if i == 1 then
+                function f1(_) r1 = i + j end
+            elseif i == 2 then
+                function f2(_) r2 = i + j end
+                break
+            end
+        end
+        f1(0)
+        f2(0)
+
+        -- 上值的上值
+        local a = 1
+        local function outer(_)
+            local function inner(_)
+                a = a + 1
+                deep = a
+            end
+            inner(0)
+        end
+        outer(0)
+        outer(0)
+
+        -- 局部函数可以递归调用自己
+        local function fact(n)
+            if n <= 1 then
+                result = 1
+            else
+                fact(n - 1)
+                result = result * n
+            end
+        end
+        fact(5)
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("count"), Value::Integer(13));
+    assert_eq!(state.global("before"), Value::Integer(2));
+    assert_eq!(state.global("got"), Value::Integer(3));
+    assert_eq!(state.global("r1"), Value::Integer(11));
+    assert_eq!(state.global("r2"), Value::Integer(22));
+    assert_eq!(state.global("deep"), Value::Integer(3));
+    assert_eq!(state.global("result"), Value::Integer(120));
+}
+
+#[test]
+fn test_return() {
+    init_log();
+    let source = indoc! {r#"
+        local function three() return 1, 2, 3 end
+        local function none() return end
+        local function collect(a, b, c, d)
+            args = (a or "nil") .. "," .. (b or "nil") .. "," .. (c or "nil") .. "," .. (d or "nil")
+        end
+
+        -- 表达式中只取第一个返回值
+        first = three() + 10
+        local n = none()
+        empty = n == nil
+
+        -- 最后一个参数展开为所有返回值，其余位置只取一个
+        collect(0, three())
+        args1 = args
+        collect(three(), 10)
+        args2 = args
+        collect((three()))
+        args3 = args
+
+        -- 返回值可以直接传给另一个 return
+        local function pass() return three() end
+        collect(pass())
+        args4 = args
+
+        -- 提前返回
+        local function find(limit)
+            for i = 1, 100 do
+                if i * i > limit then
+                    return i
+                end
+            end
+            return -1
+        end
+        found = find(50)
+
+        -- 函数返回后，上值保存返回时的值
+        local function make()
+            local x = 10
+            function get_x(_) got = x end
+            return x
+        end
+        made = make()
+        get_x()
+
+        -- 泛型 for 语句的表达式列表也会展开
+        local function iter(limit)
+            return function(s, i)
+                if i < limit then return i + 1 end
+            end, nil, 0
+        end
+        sum = 0
+        for i in iter(4) do
+            sum = sum + i
+        end
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("first"), Value::Integer(11));
+    assert_eq!(state.global("empty"), Value::Boolean(true));
+    assert_eq!(format!("{:?}", state.global("args1")), "0,1,2,3");
+    assert_eq!(format!("{:?}", state.global("args2")), "1,10,nil,nil");
+    assert_eq!(format!("{:?}", state.global("args3")), "1,nil,nil,nil");
+    assert_eq!(format!("{:?}", state.global("args4")), "1,2,3,nil");
+    assert_eq!(state.global("found"), Value::Integer(8));
+    assert_eq!(state.global("made"), Value::Integer(10));
+    assert_eq!(state.global("got"), Value::Integer(10));
+    assert_eq!(state.global("sum"), Value::Integer(10));
+
+    // return 只能是块中的最后一条语句
+    let err = rua("return 1; x = 2").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        r#"parse failed: main:1:11: unexpected token Name("x")"#
+    );
+}
+
+#[test]
+fn test_varargs() {
+    init_log();
+    let source = indoc! {r#"
+        local function count(...) return select('#', ...) end
+        local function id(...) return ... end
+        local function add(a, ...)
+            local b = ...
+            return a + b
+        end
+
+        nargs = count(1, nil, 3, nil)
+        none = count()
+        passed = count(id(1, 2, 3))
+        -- 括号和非末尾位置只取一个值
+        truncated = count((id(1, 2, 3)))
+        middle = count(id(1, 2, 3), 4)
+        sum = add(1, 2, 3)
+
+        second = select(2, "a", "b", "c")
+        after = count(select(2, "a", "b", "c"))
+        last = select(-1, "a", "b", "c")
+        beyond = count(select(5, "a", "b", "c"))
+
+        -- 泛型 for 语句的表达式列表中展开
+        local function iter(...)
+            total = 0
+            for i in ... do
+                total = total + i
+            end
+        end
+        iter(function(limit, i)
+            if i < limit then return i + 1 end
+        end, 4, 0)
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("nargs"), Value::Integer(4));
+    assert_eq!(state.global("none"), Value::Integer(0));
+    assert_eq!(state.global("passed"), Value::Integer(3));
+    assert_eq!(state.global("truncated"), Value::Integer(1));
+    assert_eq!(state.global("middle"), Value::Integer(2));
+    assert_eq!(state.global("sum"), Value::Integer(3));
+    assert_eq!(format!("{:?}", state.global("second")), "b");
+    assert_eq!(state.global("after"), Value::Integer(2));
+    assert_eq!(format!("{:?}", state.global("last")), "c");
+    assert_eq!(state.global("beyond"), Value::Integer(0));
+    assert_eq!(state.global("total"), Value::Integer(10));
+
+    // 主函数的变长参数即命令行参数
+    let proto = ParseProto::new("test", b"nargs = select('#', ...) first = ...")
+        .parse()
+        .unwrap();
+    let mut state = ExeState::new();
+    let args = vec![Value::String(b"a".as_slice().into()), Value::Integer(2)];
+    state.execute(proto, args).unwrap();
+    assert_eq!(state.global("nargs"), Value::Integer(2));
+    assert_eq!(format!("{:?}", state.global("first")), "a");
+    // 参数不符时调用 nil 而出错
+    rua_args("main", b"if ... ~= 'x' then undefined() end", &[b"x"]).unwrap();
+    rua_args("main", b"if ... ~= 'x' then undefined() end", &[b"y"]).unwrap_err();
+
+    let err = rua("function f() return ... end").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:1:21: cannot use '...' outside a vararg function"
+    );
+    let err = rua("select(0, 1)").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad argument #1 to 'select' (index out of range)"
+    );
+    let err = rua("select(nil, 1)").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad argument #1 to 'select' (number expected, got nil)"
+    );
+}
+
+#[test]
+fn test_multiple_assignment() {
+    init_log();
+    let source = indoc! {r#"
+        local function three() return 1, 2, 3 end
+
+        local a, b, c = 1, 2
+        no_c = c == nil
+        local x, y = 10, 20, undefined_extra()
+        a, b = b, a
+        swapped_a, swapped_b = a, b
+
+        -- 函数调用补足缺少的值，非末尾位置只取一个
+        local p, q, r, s = three()
+        local t, u, v = three(), 10
+        g1, g2, g3 = (three())
+        rest = s == nil and v == nil and g2 == nil
+        sum3, sum2 = p + q + r, t + u
+
+        -- 全局变量、局部变量和上值混合赋值
+        local up = 0
+        local function f()
+            up, glob, x = x, up, 30
+        end
+        f()
+        up_value, x_value = up, x
+
+        local n
+        declared = n == nil
+    "#};
+    let err = rua(source).unwrap_err();
+    assert_eq!(err.to_string(), "attempt to call a nil value");
+
+    let source = source.replace("undefined_extra()", "40");
+    let state = run(&source);
+    assert_eq!(state.global("no_c"), Value::Boolean(true));
+    assert_eq!(state.global("swapped_a"), Value::Integer(2));
+    assert_eq!(state.global("swapped_b"), Value::Integer(1));
+    assert_eq!(state.global("g1"), Value::Integer(1));
+    assert_eq!(state.global("rest"), Value::Boolean(true));
+    assert_eq!(state.global("sum3"), Value::Integer(6));
+    assert_eq!(state.global("sum2"), Value::Integer(11));
+    assert_eq!(state.global("glob"), Value::Integer(0));
+    assert_eq!(state.global("up_value"), Value::Integer(10));
+    assert_eq!(state.global("x_value"), Value::Integer(30));
+    assert_eq!(state.global("declared"), Value::Boolean(true));
+}
+
+#[test]
+fn test_call() {
+    init_log();
+    let source = indoc! {r#"
+        local function zero() return 0 end
+        local function add(a, b, c) return a + b + (c or 0) end
+        local function adder(a)
+            return function(b)
+                return function(c) return a + b + c end
+            end
+        end
+        local function concat(a)
+            return function(b) return a .. b end
+        end
+
+        z = zero()
+        local x = add(1, 2)
+        local y = add(1, 2, 3) * 2
+        chained = adder(1)(2)(3)
+        strings = concat "a" "b"
+        ;(print)("calling a parenthesized function", x, y)
+        ;(adder)(1)(2)(3)
+        print()
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("z"), Value::Integer(0));
+    assert_eq!(state.global("chained"), Value::Integer(6));
+    assert_eq!(format!("{:?}", state.global("strings")), "ab");
+
+    let err = rua("local function f() end f() = 1").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:1:28: unexpected token Assign"
+    );
+    let err = rua("local a = 1 (a) = 2").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "parse failed: main:1:17: unexpected token Assign"
+    );
+    let err = rua("x y = 1").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        r#"parse failed: main:1:3: expected token `=` but got Name("y")"#
+    );
+    let err = rua("f(1).x = 2").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to call a nil value");
+
+    // 递归过深时报错，而不是耗尽宿主的栈
+    let source = indoc! {r#"
+        local function sum(n)
+            if n == 0 then return 0 end
+            return n + sum(n - 1)
+        end
+        total = sum(150)
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("total"), Value::Integer(11325));
+    let err = rua("local function sum(n) return n == 0 and 0 or n + sum(n - 1) end sum(2000)")
+        .unwrap_err();
+    assert_eq!(err.to_string(), "stack overflow");
+    let err = rua("local function f() return f() end f()").unwrap_err();
+    assert_eq!(err.to_string(), "stack overflow");
+}
+
+#[test]
+fn test_table() {
+    init_log();
+    let source = indoc! {r#"
+        local function three() return 1, 2, 3 end
+        local function count(t)
+            local n = 0
+            for _ in pairs(t) do n = n + 1 end
+            return n
+        end
+
+        local empty = {}
+        local t = {10, 20, 30; x = 1, ["y"] = 2, [3 + 1] = 40}
+        len = #t
+        items = count(t)
+        first_key, first_value = next(t)
+        no_key = next(empty)
+
+        -- 表是引用类型
+        local alias = t
+        same = alias == t
+        different = {} == {}
+
+        -- 最后一个数组项展开为所有值
+        expanded = #{three()}
+        not_last = #{three(), three()}
+        truncated = #{(three())}
+        local function pack(...) return {...} end
+        packed = #pack(1, 2, 3, 4)
+        with_fields = #{three(), x = 1}
+
+        -- 调用的参数可以是表构造式
+        called = count{1, 2, a = 3}
+
+        -- ipairs 在第一个 nil 处停止
+        local sum = 0
+        for i, v in ipairs({1, 2, nil, 4}) do
+            sum = sum + i * v
+        end
+        ipairs_sum = sum
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("len"), Value::Integer(4));
+    assert_eq!(state.global("items"), Value::Integer(6));
+    assert_eq!(state.global("first_key"), Value::Integer(1));
+    assert_eq!(state.global("first_value"), Value::Integer(10));
+    assert_eq!(state.global("no_key"), Value::Nil);
+    assert_eq!(state.global("same"), Value::Boolean(true));
+    assert_eq!(state.global("different"), Value::Boolean(false));
+    assert_eq!(state.global("expanded"), Value::Integer(3));
+    assert_eq!(state.global("not_last"), Value::Integer(4));
+    assert_eq!(state.global("truncated"), Value::Integer(1));
+    assert_eq!(state.global("packed"), Value::Integer(4));
+    assert_eq!(state.global("with_fields"), Value::Integer(1));
+    assert_eq!(state.global("called"), Value::Integer(3));
+    assert_eq!(state.global("ipairs_sum"), Value::Integer(5));
+
+    // 大量数组项分批存入表中
+    let items = (1..=120).map(|i| i.to_string()).collect::<Vec<_>>();
+    let source = format!("local t = {{{}}} len = #t", items.join(", "));
+    let state = run(&source);
+    assert_eq!(state.global("len"), Value::Integer(120));
+
+    // 常量多于 256 个时，字段名与全局变量名的常量索引超出一个字节
+    let fields = (0..300)
+        .map(|i| format!("k{i} = \"v{i}\""))
+        .collect::<Vec<_>>();
+    let globals = (0..300)
+        .map(|i| format!("g{i} = \"s{i}\""))
+        .collect::<Vec<_>>();
+    let source = format!(
+        "local t = {{{}}} {} first, last, set = t.k0, t.k299, g299
+        t.k299 = g0 t.extra = 1 last_set, extra = t.k299, t.extra
+        function t:method() return self.k298 end method = t:method()",
+        fields.join(", "),
+        globals.join(" "),
+    );
+    let state = run(&source);
+    assert_eq!(
+        state.global("first"),
+        Value::String(b"v0".as_slice().into())
+    );
+    assert_eq!(
+        state.global("last"),
+        Value::String(b"v299".as_slice().into())
+    );
+    assert_eq!(
+        state.global("set"),
+        Value::String(b"s299".as_slice().into())
+    );
+    assert_eq!(
+        state.global("last_set"),
+        Value::String(b"s0".as_slice().into())
+    );
+    assert_eq!(state.global("extra"), Value::Integer(1));
+    assert_eq!(
+        state.global("method"),
+        Value::String(b"v298".as_slice().into())
+    );
+
+    let err = rua("pairs(1)").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad argument #1 to 'pairs' (table expected, got number)"
+    );
+    let err = rua("ipairs()").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "bad argument #1 to 'ipairs' (table expected, got no value)"
+    );
+    let err = rua("next({}, 1)").unwrap_err();
+    assert_eq!(err.to_string(), "invalid key to 'next'");
+}
+
+#[test]
+fn test_index() {
+    init_log();
+    let source = indoc! {r#"
+        local t = {10, 20, 30, x = 1, ["key with space"] = 2}
+        x = t.x
+        spaced = t["key with space"]
+        second = t[2]
+        local k = 3
+        third = t[k]
+        missing = t.y
+
+        -- 写入字段，包括嵌套的表
+        t.y = 3
+        t[4] = 40
+        t[k] = 300
+        t[1000] = 1000
+        t.a = {b = {}}
+        t.a.b.c = "deep"
+        y, fourth, replaced, big, deep = t.y, t[4], t[3], t[1000], t.a.b.c
+        t.x = nil
+        removed = t.x
+
+        -- 先求值再赋值，可以交换表项
+        t[1], t[2] = t[2], t[1]
+        first, second_after = t[1], t[2]
+        local i = 1
+        i, t[i] = 2, "assigned"
+        conflict, untouched = t[1], t[2]
+
+        -- 方法的定义与调用
+        local account = {balance = 100}
+        function account.deposit(self, n) self.balance = self.balance + n end
+        function account:withdraw(n)
+            self.balance = self.balance - n
+            return self
+        end
+        account:deposit(50)
+        account:withdraw(30):withdraw(20)
+        balance = account.balance
+
+        lib = {util = {}}
+        function lib.util.twice(x) return x * 2 end
+        function lib.util:name() return self == lib.util end
+        twice = lib.util.twice(21)
+        is_self = lib.util:name()
+
+        -- 调用表中的函数时，多个返回值不混入表或键
+        local function two() return 1, 2 end
+        local nested = {a = {b = {c = two}}}
+        global_nested = nested
+        local calls = {two, f = two}
+        local j = 1
+        nested_count = select('#', nested.a.b.c())
+        nested_len = #{nested.a.b.c()}
+        indexed_count = select('#', calls[j + 0]())
+        global_count = select('#', global_nested.a.b.c())
+        local function forward() return calls.f() end
+        returned_count = select('#', forward())
+        returned_first, returned_second = forward()
+    "#};
+    let state = run(source);
+    assert_eq!(state.global("x"), Value::Integer(1));
+    assert_eq!(state.global("spaced"), Value::Integer(2));
+    assert_eq!(state.global("second"), Value::Integer(20));
+    assert_eq!(state.global("third"), Value::Integer(30));
+    assert_eq!(state.global("missing"), Value::Nil);
+    assert_eq!(state.global("y"), Value::Integer(3));
+    assert_eq!(state.global("fourth"), Value::Integer(40));
+    assert_eq!(state.global("replaced"), Value::Integer(300));
+    assert_eq!(state.global("big"), Value::Integer(1000));
+    assert_eq!(
+        state.global("deep"),
+        Value::String(b"deep".as_slice().into())
+    );
+    assert_eq!(state.global("removed"), Value::Nil);
+    assert_eq!(state.global("first"), Value::Integer(20));
+    assert_eq!(state.global("second_after"), Value::Integer(10));
+    assert_eq!(
+        state.global("conflict"),
+        Value::String(b"assigned".as_slice().into())
+    );
+    assert_eq!(state.global("untouched"), Value::Integer(10));
+    assert_eq!(state.global("balance"), Value::Integer(100));
+    assert_eq!(state.global("twice"), Value::Integer(42));
+    assert_eq!(state.global("is_self"), Value::Boolean(true));
+    assert_eq!(state.global("nested_count"), Value::Integer(2));
+    assert_eq!(state.global("nested_len"), Value::Integer(2));
+    assert_eq!(state.global("indexed_count"), Value::Integer(2));
+    assert_eq!(state.global("global_count"), Value::Integer(2));
+    assert_eq!(state.global("returned_count"), Value::Integer(2));
+    assert_eq!(state.global("returned_first"), Value::Integer(1));
+    assert_eq!(state.global("returned_second"), Value::Integer(2));
+
+    let err = rua("cfg.debug = true").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "attempt to index a nil value (global 'cfg')"
+    );
+    let err = rua("local t = 1 print(t[1])").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "attempt to index a number value (local 't')"
+    );
+    let err = rua("local t = {} t.a.b = 1").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to index a nil value (field 'a')");
+    let err = rua("local t function f() return t.x end f()").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "attempt to index a nil value (upvalue 't')"
+    );
+    let err = rua("local obj obj:method()").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "attempt to index a nil value (local 'obj')"
+    );
+    let err = rua("local function f() end f().x = 1").unwrap_err();
+    assert_eq!(err.to_string(), "attempt to index a nil value");
+}
+
+#[test]
+fn test_hybrid_table() {
+    init_log();
+    let source = indoc! {r#"
+        -- 值为整数的浮点数键等同于整数键
+        local t = {}
+        t[1.0] = "one"
+        t[2] = "two"
+        int_key = t[1]
+        float_key = t[2.0]
+        t[1.5] = "half"
+        half = t[1.5]
+        t[2^53] = "big"
+        big = t[1 << 53]
+
+        -- 先写入哈希部分的键在数组增长时移入数组部分
+        local a = {}
+        a[3] = 3
+        a[2] = 2
+        a[1] = 1
+        migrated = #a
+
+        -- 边界：t[n] 不为 nil 而 t[n + 1] 为 nil
+        local holes = {1, 2, 3, 4, 5}
+        holes[5] = nil
+        shrunk = #holes
+        holes[4] = nil
+        holes[3] = nil
+        border = #holes
+        empty_len = #{nil, nil}
+
+        -- 遍历时可以修改或清除已有的字段
+        local m = {10, 20, x = 1, y = 2, z = 3}
+        local visited = 0
+        for k, v in pairs(m) do
+            visited = visited + 1
+            m[k] = nil
+        end
+        cleared = visited
+        left = next(m)
+
+        local n = {a = 1, b = 2, c = 3}
+        local sum = 0
+        for k, v in pairs(n) do
+            n[k] = v * 10
+            sum = sum + n[k]
+        end
+        doubled = sum
+
+        -- 哈希部分按插入顺序遍历，清除的键不影响其余键的顺序
+        local o = {}
+        o.first = 1
+        o.second = 2
+        o.third = 3
+        o.second = nil
+        o.fourth = 4
+        local order = ""
+        for k in pairs(o) do order = order .. k .. " " end
+        ordered = order
+    "#};
+    let state = run(source);
+    assert_eq!(
+        state.global("int_key"),
+        Value::String(b"one".as_slice().into())
+    );
+    assert_eq!(
+        state.global("float_key"),
+        Value::String(b"two".as_slice().into())
+    );
+    assert_eq!(
+        state.global("half"),
+        Value::String(b"half".as_slice().into())
+    );
+    assert_eq!(state.global("big"), Value::String(b"big".as_slice().into()));
+    assert_eq!(state.global("migrated"), Value::Integer(3));
+    assert_eq!(state.global("shrunk"), Value::Integer(4));
+    assert_eq!(state.global("border"), Value::Integer(2));
+    assert_eq!(state.global("empty_len"), Value::Integer(0));
+    assert_eq!(state.global("cleared"), Value::Integer(5));
+    assert_eq!(state.global("left"), Value::Nil);
+    assert_eq!(state.global("doubled"), Value::Integer(60));
+    assert_eq!(
+        state.global("ordered"),
+        Value::String(b"first third fourth ".as_slice().into())
+    );
+
+    let err = rua("local t = {} t[nil] = 1").unwrap_err();
+    assert_eq!(err.to_string(), "table index is nil");
+    let err = rua("local t = {} t[0/0] = 1").unwrap_err();
+    assert_eq!(err.to_string(), "table index is NaN");
+    let err = rua("local t = {[nil] = 1}").unwrap_err();
+    assert_eq!(err.to_string(), "table index is nil");
+    // 读取 nil 与 NaN 键只是得到 nil
+    let state = run("local t = {} a, b = t[nil], t[0/0]");
+    assert_eq!(state.global("a"), Value::Nil);
+    assert_eq!(state.global("b"), Value::Nil);
+}
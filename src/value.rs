@@ -1,24 +1,62 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use smol_str::SmolStr;
 
 use crate::str::LossyStr;
-use crate::ExeState;
+use crate::{ExeState, FuncProto, Table};
 
-pub type LuaFunc = fn(&mut ExeState) -> i32;
+pub type LuaFunc = fn(&mut ExeState) -> anyhow::Result<i32>;
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Default)]
 pub enum Value {
+    #[default]
     Nil,
     Boolean(bool),
     Integer(i64),
     Float(f64),
     String(LossyStr),
     Function(LuaFunc),
+    LuaFunction(Rc<LuaClosure>),
+    Table(Rc<RefCell<Table>>),
     Identifier(SmolStr),
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Self::Nil
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Boolean(a), Self::Boolean(b)) => a == b,
+            (Self::Integer(a), Self::Integer(b)) => a == b,
+            (Self::Float(a), Self::Float(b)) => a == b,
+            (Self::String(a), Self::String(b)) => a == b,
+            (Self::Function(a), Self::Function(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Self::LuaFunction(a), Self::LuaFunction(b)) => Rc::ptr_eq(a, b),
+            (Self::Table(a), Self::Table(b)) => Rc::ptr_eq(a, b),
+            (Self::Identifier(a), Self::Identifier(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+// 浮点数 NaN 不等于自身，但它不能作为表的键
+impl Eq for Value {}
+
+impl std::hash::Hash for Value {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Nil => {}
+            Self::Boolean(b) => b.hash(state),
+            Self::Integer(i) => i.hash(state),
+            // 0.0 与 -0.0 相等，散列值也必须相同
+            Self::Float(f) => (if *f == 0.0 { 0.0 } else { *f }).to_bits().hash(state),
+            Self::String(s) => s.as_bytes().hash(state),
+            Self::Function(f) => (*f as usize).hash(state),
+            Self::LuaFunction(f) => Rc::as_ptr(f).hash(state),
+            Self::Table(t) => Rc::as_ptr(t).hash(state),
+            Self::Identifier(s) => s.hash(state),
+        }
     }
 }
 
@@ -28,15 +66,34 @@ impl std::fmt::Debug for Value {
             Self::Nil => f.write_str("nil"),
             Self::Boolean(b) => write!(f, "{b}"),
             Self::Integer(i) => write!(f, "{i}"),
-            Self::Float(x) => write!(f, "{x:?}"),
+            Self::Float(x) => fmt_float(f, *x),
             Self::String(s) => write!(f, "{s}"),
             Self::Identifier(s) => f.write_str(s),
             Self::Function(func) => write!(f, "function: {func:#x?}"),
+            Self::LuaFunction(closure) => write!(f, "function: {:p}", Rc::as_ptr(closure)),
+            Self::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
         }
     }
 }
 
 impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::Boolean(_) => "boolean",
+            Self::Integer(_) | Self::Float(_) => "number",
+            Self::String(_) => "string",
+            Self::Function(_) | Self::LuaFunction(_) => "function",
+            Self::Table(_) => "table",
+            Self::Identifier(_) => "identifier",
+        }
+    }
+
+    /// Only `nil` and `false` are falsy in Lua.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Self::Nil | Self::Boolean(false))
+    }
+
     pub fn as_identifier(&self) -> Option<&SmolStr> {
         match self {
             Self::Identifier(s) => Some(s),
@@ -44,3 +101,55 @@ impl Value {
         }
     }
 }
+
+/// A Lua function with the upvalues captured when it was created.
+#[derive(Debug)]
+pub struct LuaClosure {
+    pub proto: Rc<FuncProto>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// A variable of an enclosing function captured by a closure.
+///
+/// It refers to the stack slot while the variable is alive, and holds
+/// the value itself once the variable goes out of scope, so that all
+/// the closures sharing it always see the same value.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// Format a float like Lua does with `%.14g`,
+/// appending `.0` if it looks like an integer.
+fn fmt_float(f: &mut std::fmt::Formatter<'_>, x: f64) -> std::fmt::Result {
+    if x.is_nan() {
+        return f.write_str(if x.is_sign_negative() { "-nan" } else { "nan" });
+    }
+    if x.is_infinite() {
+        return f.write_str(if x < 0.0 { "-inf" } else { "inf" });
+    }
+
+    // 先按 14 位有效数字取整，再根据指数决定记法
+    let sci = format!("{x:.13e}");
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+
+    if (-4..14).contains(&exp) {
+        let s = format!("{x:.*}", (13 - exp) as usize);
+        let s = if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            &s
+        };
+        if s.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+            write!(f, "{s}.0")
+        } else {
+            f.write_str(s)
+        }
+    } else {
+        let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+        let sign = if exp < 0 { '-' } else { '+' };
+        write!(f, "{mantissa}e{sign}{:02}", exp.abs())
+    }
+}
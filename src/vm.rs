@@ -1,58 +1,128 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
+use anyhow::bail;
 use smol_str::SmolStr;
 
-use crate::{ByteCode, ParseProto, Value};
+use crate::parse::UpvalDesc;
+use crate::{arith, debug, ByteCode, FuncProto, LuaClosure, Table, Upvalue, Value};
 
 #[derive(Debug)]
 pub struct ExeState {
     globals: HashMap<SmolStr, Value>,
     stack: Vec<Value>,
     func_index: usize,
+    /// Upvalues still referring to the stack, ordered by their slots
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// 正在执行的 Lua 函数的嵌套层数
+    depth: usize,
 }
 
+/// Maximum nesting depth of Lua function calls, each of which takes
+/// a frame on the native stack.
+const MAX_CALL_DEPTH: usize = 200;
+
 impl ExeState {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        let globals =
-            HashMap::from_iter([(SmolStr::new("print"), Value::Function(Self::lib_print))]);
+        let globals = HashMap::from_iter([
+            (SmolStr::new("print"), Value::Function(Self::lib_print)),
+            (SmolStr::new("select"), Value::Function(Self::lib_select)),
+            (SmolStr::new("next"), Value::Function(Self::lib_next)),
+            (SmolStr::new("pairs"), Value::Function(Self::lib_pairs)),
+            (SmolStr::new("ipairs"), Value::Function(Self::lib_ipairs)),
+        ]);
         Self {
             globals,
             stack: Vec::new(),
             func_index: 0,
+            open_upvalues: Vec::new(),
+            depth: 0,
         }
     }
 
-    pub fn execute(&mut self, proto: ParseProto) -> anyhow::Result<()> {
-        for code in proto.bytecodes {
+    /// Execute the main chunk with `args` as its varargs.
+    pub fn execute(&mut self, proto: FuncProto, args: Vec<Value>) -> anyhow::Result<()> {
+        let closure = LuaClosure {
+            proto: Rc::new(proto),
+            upvalues: Vec::new(),
+        };
+        self.execute_closure(&closure, 0, &args)?;
+        Ok(())
+    }
+
+    /// Execute `closure` whose registers start from `base` in the stack,
+    /// and return the number of results, which are left on the top of the stack.
+    fn execute_closure(
+        &mut self,
+        closure: &LuaClosure,
+        base: usize,
+        varargs: &[Value],
+    ) -> anyhow::Result<usize> {
+        let proto = &closure.proto;
+        // 寄存器在栈中的位置
+        let r = |i: u8| base + i as usize;
+
+        let mut pc = 0;
+        while let Some(&code) = proto.bytecodes.get(pc) {
             tracing::trace!("executing {code:?}");
+            pc += 1;
             match code {
                 ByteCode::GetGlobal(dst, name) => {
                     let key = proto.constants[name as usize].as_identifier().unwrap();
-                    self.set_stack(dst, self.globals.get(key).cloned().unwrap_or_default());
+                    self.set_stack(r(dst), self.globals.get(key).cloned().unwrap_or_default());
                 }
                 ByteCode::Move(dst, src) => {
-                    self.set_stack(dst, self.stack[src as usize].clone());
+                    self.set_stack(r(dst), self.stack[r(src)].clone());
                 }
                 ByteCode::LoadNil(dst) => {
-                    self.set_stack(dst, Value::Nil);
+                    self.set_stack(r(dst), Value::Nil);
                 }
                 ByteCode::LoadBool(dst, b) => {
-                    self.set_stack(dst, Value::Boolean(b));
+                    self.set_stack(r(dst), Value::Boolean(b));
                 }
                 ByteCode::LoadInt(dst, i) => {
-                    self.set_stack(dst, Value::Integer(i as i64));
+                    self.set_stack(r(dst), Value::Integer(i as i64));
                 }
                 ByteCode::LoadConst(dst, c) => {
-                    self.set_stack(dst, proto.constants[c as usize].clone());
+                    self.set_stack(r(dst), proto.constants[c as usize].clone());
+                }
+                ByteCode::Call(func, narg_plus, want_plus) => {
+                    let func = r(func);
+                    // 参数个数不定时，参数延续到栈顶
+                    let narg = if narg_plus == 0 {
+                        self.stack.len() - func - 1
+                    } else {
+                        narg_plus as usize - 1
+                    };
+                    let nret = self.call(func, narg)?;
+                    let want = (want_plus != 0).then(|| want_plus as usize - 1);
+                    self.place_results(func, nret, want);
                 }
-                ByteCode::Call(func, _) => {
-                    self.func_index = func as usize;
-                    let func = match &self.stack[self.func_index] {
-                        Value::Function(func) => func,
-                        v => anyhow::bail!("{v:?} is not a function"),
+                ByteCode::Return(iret, nret_plus) => {
+                    let iret = r(iret);
+                    let nret = if nret_plus == 0 {
+                        self.stack.len() - iret
+                    } else {
+                        nret_plus as usize - 1
                     };
-                    func(self);
+                    // 返回值之下的局部变量即将被移出栈
+                    self.close_upvalues(base);
+                    self.stack.resize(iret + nret, Value::Nil);
+                    return Ok(nret);
+                }
+                ByteCode::VarArgs(dst, want_plus) => {
+                    let dst = r(dst);
+                    if want_plus == 0 {
+                        // 全部展开到栈顶
+                        self.stack.truncate(dst);
+                        self.stack.extend_from_slice(varargs);
+                    } else {
+                        for i in 0..want_plus as usize - 1 {
+                            self.set_stack(dst + i, varargs.get(i).cloned().unwrap_or_default());
+                        }
+                    }
                 }
                 ByteCode::SetGlobalConst(gi, ki) => {
                     self.globals.insert(
@@ -69,7 +139,7 @@ impl ExeState {
                             .as_identifier()
                             .unwrap()
                             .to_owned(),
-                        self.stack[src as usize].clone(),
+                        self.stack[r(src)].clone(),
                     );
                 }
                 ByteCode::SetGlobalGlobal(lhsi, rhsi) => {
@@ -86,26 +156,562 @@ impl ExeState {
                         rhs,
                     );
                 }
+
+                // 表
+                ByteCode::NewTable(dst, narray, nhash) => {
+                    let table = Table::new(narray as usize, nhash as usize);
+                    self.set_stack(r(dst), Value::Table(Rc::new(RefCell::new(table))));
+                }
+                ByteCode::GetTable(dst, t, key) => {
+                    let table = self.index_table(proto, pc - 1, base, t)?;
+                    let value = table.borrow().get(&self.stack[r(key)]);
+                    self.set_stack(r(dst), value);
+                }
+                ByteCode::GetField(dst, t, key) => {
+                    let table = self.index_table(proto, pc - 1, base, t)?;
+                    let value = table.borrow().get(&proto.constants[key as usize]);
+                    self.set_stack(r(dst), value);
+                }
+                ByteCode::GetI(dst, t, i) => {
+                    let table = self.index_table(proto, pc - 1, base, t)?;
+                    let value = table.borrow().get(&Value::Integer(i as i64));
+                    self.set_stack(r(dst), value);
+                }
+                ByteCode::SetTable(t, key, value) => {
+                    let table = self.index_table(proto, pc - 1, base, t)?;
+                    let key = self.stack[r(key)].clone();
+                    table.borrow_mut().set(key, self.stack[r(value)].clone())?;
+                }
+                ByteCode::SetField(t, key, value) => {
+                    let table = self.index_table(proto, pc - 1, base, t)?;
+                    let key = proto.constants[key as usize].clone();
+                    table.borrow_mut().set(key, self.stack[r(value)].clone())?;
+                }
+                ByteCode::SetI(t, i, value) => {
+                    let table = self.index_table(proto, pc - 1, base, t)?;
+                    let key = Value::Integer(i as i64);
+                    table.borrow_mut().set(key, self.stack[r(value)].clone())?;
+                }
+                ByteCode::SetList(t, n, offset) => {
+                    let t = r(t);
+                    // 个数不定时，值延续到栈顶
+                    let n = if n == 0 {
+                        self.stack.len() - t - 1
+                    } else {
+                        n as usize
+                    };
+                    for i in 1..=n {
+                        let key = Value::Integer(offset as i64 + i as i64);
+                        let value = self.stack[t + i].clone();
+                        self.set_table(t, key, value)?;
+                    }
+                }
+
+                // 闭包
+                ByteCode::GetUpval(dst, idx) => {
+                    let value = match &*closure.upvalues[idx as usize].borrow() {
+                        Upvalue::Open(i) => self.stack[*i].clone(),
+                        Upvalue::Closed(v) => v.clone(),
+                    };
+                    self.set_stack(r(dst), value);
+                }
+                ByteCode::SetUpval(idx, src) => {
+                    let value = self.stack[r(src)].clone();
+                    match &mut *closure.upvalues[idx as usize].borrow_mut() {
+                        Upvalue::Open(i) => self.stack[*i] = value,
+                        Upvalue::Closed(v) => *v = value,
+                    }
+                }
+                ByteCode::Closure(dst, idx) => {
+                    let proto = proto.protos[idx as usize].clone();
+                    let upvalues = proto
+                        .upvalues
+                        .iter()
+                        .map(|desc| match *desc {
+                            UpvalDesc::Local(reg) => self.open_upvalue(base + reg),
+                            UpvalDesc::Upvalue(i) => closure.upvalues[i].clone(),
+                        })
+                        .collect();
+                    let closure = LuaClosure { proto, upvalues };
+                    self.set_stack(r(dst), Value::LuaFunction(Rc::new(closure)));
+                }
+                ByteCode::Close(src) => self.close_upvalues(r(src)),
+
+                // 跳转
+                ByteCode::Jump(jmp) => {
+                    pc = pc.wrapping_add_signed(jmp as isize);
+                }
+                ByteCode::Test(cond, jmp) => {
+                    if !self.stack[r(cond)].is_truthy() {
+                        pc = pc.wrapping_add_signed(jmp as isize);
+                    }
+                }
+                ByteCode::TestAndJump(src, jmp) => {
+                    if !self.stack[r(src)].is_truthy() {
+                        pc = pc.wrapping_add_signed(jmp as isize);
+                    }
+                }
+                ByteCode::TestOrJump(src, jmp) => {
+                    if self.stack[r(src)].is_truthy() {
+                        pc = pc.wrapping_add_signed(jmp as isize);
+                    }
+                }
+
+                // 数值 for 循环
+                ByteCode::ForPrep(base, jmp) => {
+                    if !self.for_prep(r(base))? {
+                        pc += jmp as usize;
+                    }
+                }
+                ByteCode::ForLoop(base, jmp) => {
+                    if self.for_loop(r(base)) {
+                        pc -= jmp as usize;
+                    }
+                }
+
+                // 泛型 for 循环
+                ByteCode::TForPrep(base, jmp) => {
+                    // 没有元表，所以只有 nil 和 false 可以关闭
+                    if self.stack[r(base) + 3].is_truthy() {
+                        bail!("variable '(for state)' got a non-closable value");
+                    }
+                    pc += jmp as usize;
+                }
+                ByteCode::TForCall(base, nvar) => {
+                    let base = r(base);
+                    // 在循环变量的位置调用迭代函数，保留内部状态
+                    for i in 0..3 {
+                        self.set_stack(base + 4 + i, self.stack[base + i].clone());
+                    }
+                    let nret = self.call(base + 4, 2)?;
+                    self.place_results(base + 4, nret, Some(nvar as usize));
+                }
+                ByteCode::TForLoop(base, jmp) => {
+                    let base = r(base);
+                    if !matches!(self.stack[base + 4], Value::Nil) {
+                        self.stack[base + 2] = self.stack[base + 4].clone();
+                        pc -= jmp as usize;
+                    }
+                }
+
+                // 运算
+                ByteCode::Add(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::add)?,
+                ByteCode::Sub(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::sub)?,
+                ByteCode::Mul(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::mul)?,
+                ByteCode::Div(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::div)?,
+                ByteCode::Idiv(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::idiv)?,
+                ByteCode::Mod(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::modulo)?,
+                ByteCode::Pow(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::pow)?,
+                ByteCode::BitAnd(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::bit_and)?,
+                ByteCode::BitOr(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::bit_or)?,
+                ByteCode::BitXor(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::bit_xor)?,
+                ByteCode::ShiftL(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::shift_left)?,
+                ByteCode::ShiftR(dst, a, b) => {
+                    self.binop(r(dst), r(a), r(b), arith::shift_right)?
+                }
+                ByteCode::Concat(dst, a, b) => self.binop(r(dst), r(a), r(b), arith::concat)?,
+                ByteCode::Unm(dst, src) => self.unop(r(dst), r(src), arith::unm)?,
+                ByteCode::BitNot(dst, src) => self.unop(r(dst), r(src), arith::bit_not)?,
+                ByteCode::Len(dst, src) => self.unop(r(dst), r(src), arith::len)?,
+                ByteCode::Not(dst, src) => {
+                    self.unop(r(dst), r(src), |v| Ok(Value::Boolean(!v.is_truthy())))?
+                }
+
+                // 比较
+                ByteCode::Equal(dst, a, b) => self.binop(r(dst), r(a), r(b), |a, b| {
+                    Ok(Value::Boolean(arith::equal(a, b)))
+                })?,
+                ByteCode::NotEq(dst, a, b) => self.binop(r(dst), r(a), r(b), |a, b| {
+                    Ok(Value::Boolean(!arith::equal(a, b)))
+                })?,
+                ByteCode::Less(dst, a, b) => self.binop(r(dst), r(a), r(b), |a, b| {
+                    arith::less(a, b).map(Value::Boolean)
+                })?,
+                ByteCode::LesEq(dst, a, b) => self.binop(r(dst), r(a), r(b), |a, b| {
+                    arith::less_eq(a, b).map(Value::Boolean)
+                })?,
             };
             tracing::trace!("stack: {:#?}", self.stack);
         }
 
-        Ok(())
+        // 没有 return 语句
+        self.close_upvalues(base);
+        Ok(0)
     }
 }
 
 impl ExeState {
-    fn set_stack(&mut self, dst: u8, value: Value) {
-        if let Some(v) = self.stack.get_mut(dst as usize) {
+    #[cfg(test)]
+    pub fn global(&self, name: &str) -> Value {
+        self.globals.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Call the function in `R[func]` with `narg` arguments above it,
+    /// and return the number of results, which are left on the top of the stack.
+    fn call(&mut self, func: usize, narg: usize) -> anyhow::Result<usize> {
+        self.func_index = func;
+        self.stack.truncate(func + 1 + narg);
+        match &self.stack[func] {
+            Value::Function(f) => Ok(f(self)? as usize),
+            Value::LuaFunction(closure) => {
+                let closure = closure.clone();
+                let nparam = closure.proto.nparam;
+                // 多余的实参作为变长参数，或被丢弃；缺少的形参为 nil
+                let varargs = if closure.proto.is_vararg && narg > nparam {
+                    self.stack.split_off(func + 1 + nparam)
+                } else {
+                    Vec::new()
+                };
+                self.stack.resize(func + 1 + nparam, Value::Nil);
+
+                if self.depth >= MAX_CALL_DEPTH {
+                    bail!("stack overflow");
+                }
+                self.depth += 1;
+                let nret = self.execute_closure(&closure, func + 1, &varargs);
+                self.depth -= 1;
+                nret
+            }
+            v => bail!("attempt to call a {} value", v.type_name()),
+        }
+    }
+
+    fn set_table(&mut self, t: usize, key: Value, value: Value) -> anyhow::Result<()> {
+        match &self.stack[t] {
+            Value::Table(table) => table.borrow_mut().set(key, value),
+            v => bail!("attempt to index a {} value", v.type_name()),
+        }
+    }
+
+    /// Get the table in register `t` to be indexed by the bytecode at `pc`,
+    /// or an error naming the variable which is not a table.
+    fn index_table(
+        &self,
+        proto: &FuncProto,
+        pc: usize,
+        base: usize,
+        t: u8,
+    ) -> anyhow::Result<Rc<RefCell<Table>>> {
+        match &self.stack[base + t as usize] {
+            Value::Table(table) => Ok(table.clone()),
+            v => match debug::var_info(proto, pc, t as usize) {
+                Some(info) => bail!("attempt to index a {} value ({info})", v.type_name()),
+                None => bail!("attempt to index a {} value", v.type_name()),
+            },
+        }
+    }
+
+    /// Move the `nret` results on the top of the stack to `dst` onwards,
+    /// adjusting them to `want` values, or keeping all of them if `want` is `None`.
+    fn place_results(&mut self, dst: usize, nret: usize, want: Option<usize>) {
+        let start = self.stack.len() - nret;
+        self.stack.drain(dst..start);
+        if let Some(want) = want {
+            self.stack.resize(dst + want, Value::Nil);
+        }
+    }
+
+    /// Get the open upvalue referring to stack slot `idx`,
+    /// which is shared by all the closures capturing the same variable.
+    fn open_upvalue(&mut self, idx: usize) -> Rc<RefCell<Upvalue>> {
+        let pos = self
+            .open_upvalues
+            .partition_point(|up| matches!(*up.borrow(), Upvalue::Open(i) if i < idx));
+        if let Some(up) = self.open_upvalues.get(pos) {
+            if matches!(*up.borrow(), Upvalue::Open(i) if i == idx) {
+                return up.clone();
+            }
+        }
+
+        let up = Rc::new(RefCell::new(Upvalue::Open(idx)));
+        self.open_upvalues.insert(pos, up.clone());
+        up
+    }
+
+    /// Close the open upvalues referring to stack slots from `level` up,
+    /// moving the values out of the stack into them.
+    fn close_upvalues(&mut self, level: usize) {
+        let pos = self
+            .open_upvalues
+            .partition_point(|up| matches!(*up.borrow(), Upvalue::Open(i) if i < level));
+        for up in self.open_upvalues.drain(pos..) {
+            let mut up = up.borrow_mut();
+            let Upvalue::Open(i) = *up else {
+                unreachable!()
+            };
+            *up = Upvalue::Closed(self.stack.get(i).cloned().unwrap_or_default());
+        }
+    }
+
+    fn set_stack(&mut self, dst: usize, value: Value) {
+        if let Some(v) = self.stack.get_mut(dst) {
             *v = value;
         } else {
+            // 临时寄存器可能越过栈顶
+            self.stack.resize(dst, Value::Nil);
             self.stack.push(value);
-            assert_eq!(dst as usize, self.stack.len() - 1);
         }
     }
 
-    fn lib_print(&mut self) -> i32 {
-        println!("{:?}", self.stack[self.func_index + 1]);
-        0
+    /// Check the initial value, limit and step of a numeric `for` loop
+    /// in `R[base]`, `R[base+1]` and `R[base+2]`, and return whether the loop runs.
+    ///
+    /// An integer loop keeps its iteration count in `R[base+1]` instead of the limit.
+    fn for_prep(&mut self, base: usize) -> anyhow::Result<bool> {
+        if let (&Value::Integer(init), &Value::Integer(step)) =
+            (&self.stack[base], &self.stack[base + 2])
+        {
+            if step == 0 {
+                bail!("'for' step is zero");
+            }
+            let Some(limit) = for_limit(&self.stack[base + 1], step)? else {
+                return Ok(false);
+            };
+            if (step > 0 && init > limit) || (step < 0 && init < limit) {
+                return Ok(false);
+            }
+
+            // 预先算出循环次数，循环变量因此不会溢出
+            let count = if step > 0 {
+                (limit as u64).wrapping_sub(init as u64) / step as u64
+            } else {
+                (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+            };
+            self.stack[base + 1] = Value::Integer(count as i64);
+            self.set_stack(base + 3, Value::Integer(init));
+        } else {
+            let Some(limit) = arith::to_float(&self.stack[base + 1]) else {
+                bail!("'for' limit must be a number");
+            };
+            let Some(step) = arith::to_float(&self.stack[base + 2]) else {
+                bail!("'for' step must be a number");
+            };
+            let Some(init) = arith::to_float(&self.stack[base]) else {
+                bail!("'for' initial value must be a number");
+            };
+            if step == 0.0 {
+                bail!("'for' step is zero");
+            }
+            // 与 NaN 比较总为假，此时循环不执行
+            let run = if step > 0.0 {
+                init <= limit
+            } else {
+                limit <= init
+            };
+            if !run {
+                return Ok(false);
+            }
+
+            self.stack[base] = Value::Float(init);
+            self.stack[base + 1] = Value::Float(limit);
+            self.stack[base + 2] = Value::Float(step);
+            self.set_stack(base + 3, Value::Float(init));
+        }
+
+        Ok(true)
+    }
+
+    /// Step a numeric `for` loop prepared by [`Self::for_prep`],
+    /// and return whether it continues.
+    fn for_loop(&mut self, base: usize) -> bool {
+        match self.stack[base + 2] {
+            Value::Integer(step) => {
+                let Value::Integer(count) = self.stack[base + 1] else {
+                    unreachable!()
+                };
+                // 循环次数视为无符号数
+                if count == 0 {
+                    return false;
+                }
+                let Value::Integer(i) = self.stack[base] else {
+                    unreachable!()
+                };
+
+                let i = i.wrapping_add(step);
+                self.stack[base] = Value::Integer(i);
+                self.stack[base + 1] = Value::Integer(count.wrapping_sub(1));
+                self.stack[base + 3] = Value::Integer(i);
+                true
+            }
+            Value::Float(step) => {
+                let (Value::Float(i), Value::Float(limit)) =
+                    (&self.stack[base], &self.stack[base + 1])
+                else {
+                    unreachable!()
+                };
+
+                let i = i + step;
+                let goon = if step > 0.0 { i <= *limit } else { *limit <= i };
+                if goon {
+                    self.stack[base] = Value::Float(i);
+                    self.stack[base + 3] = Value::Float(i);
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn binop(
+        &mut self,
+        dst: usize,
+        a: usize,
+        b: usize,
+        op: fn(&Value, &Value) -> anyhow::Result<Value>,
+    ) -> anyhow::Result<()> {
+        let value = op(&self.stack[a], &self.stack[b])?;
+        self.set_stack(dst, value);
+        Ok(())
+    }
+
+    fn unop(
+        &mut self,
+        dst: usize,
+        src: usize,
+        op: fn(&Value) -> anyhow::Result<Value>,
+    ) -> anyhow::Result<()> {
+        let value = op(&self.stack[src])?;
+        self.set_stack(dst, value);
+        Ok(())
+    }
+
+    fn lib_print(&mut self) -> anyhow::Result<i32> {
+        let args = &self.stack[self.func_index + 1..];
+        let line = args
+            .iter()
+            .map(|arg| format!("{arg:?}"))
+            .collect::<Vec<_>>()
+            .join("\t");
+        println!("{line}");
+        Ok(0)
+    }
+
+    /// The argument `n` (1-based) of a native function.
+    fn arg(&self, n: usize) -> &Value {
+        self.stack.get(self.func_index + n).unwrap_or(&Value::Nil)
+    }
+
+    /// Check that the argument `n` of the native function `name` is a table.
+    fn table_arg(&self, n: usize, name: &str) -> anyhow::Result<Rc<RefCell<Table>>> {
+        match self.arg(n) {
+            Value::Table(t) => Ok(t.clone()),
+            v => {
+                let got = if self.func_index + n < self.stack.len() {
+                    v.type_name()
+                } else {
+                    "no value"
+                };
+                bail!("bad argument #{n} to '{name}' (table expected, got {got})")
+            }
+        }
+    }
+
+    /// `next(t, k)` returns the key and value following `k` in `t`,
+    /// or nil at the end of the traversal.
+    fn lib_next(&mut self) -> anyhow::Result<i32> {
+        let table = self.table_arg(1, "next")?;
+        let item = table.borrow().next(self.arg(2))?;
+        match item {
+            Some((k, v)) => {
+                self.stack.push(k);
+                self.stack.push(v);
+                Ok(2)
+            }
+            None => {
+                self.stack.push(Value::Nil);
+                Ok(1)
+            }
+        }
+    }
+
+    /// `pairs(t)` returns `next, t, nil` for traversing all the items of `t`.
+    fn lib_pairs(&mut self) -> anyhow::Result<i32> {
+        let table = self.table_arg(1, "pairs")?;
+        self.stack.push(Value::Function(Self::lib_next));
+        self.stack.push(Value::Table(table));
+        self.stack.push(Value::Nil);
+        Ok(3)
+    }
+
+    /// `ipairs(t)` returns an iterator over `t[1]`, `t[2]`, ... until the first nil.
+    fn lib_ipairs(&mut self) -> anyhow::Result<i32> {
+        let table = self.table_arg(1, "ipairs")?;
+        self.stack.push(Value::Function(Self::ipairs_iter));
+        self.stack.push(Value::Table(table));
+        self.stack.push(Value::Integer(0));
+        Ok(3)
+    }
+
+    fn ipairs_iter(&mut self) -> anyhow::Result<i32> {
+        let table = self.table_arg(1, "ipairs")?;
+        let Value::Integer(i) = *self.arg(2) else {
+            bail!("bad argument #2 to 'ipairs' (number expected)");
+        };
+        let i = i.wrapping_add(1);
+        let value = table.borrow().get(&Value::Integer(i));
+        if matches!(value, Value::Nil) {
+            self.stack.push(Value::Nil);
+            return Ok(1);
+        }
+        self.stack.push(Value::Integer(i));
+        self.stack.push(value);
+        Ok(2)
+    }
+
+    /// `select('#', ...)` returns the number of the varargs,
+    /// and `select(n, ...)` returns those from the `n`-th on,
+    /// where a negative `n` counts from the end.
+    fn lib_select(&mut self) -> anyhow::Result<i32> {
+        // 包括第一个参数在内的参数个数
+        let top = (self.stack.len() - self.func_index - 1) as i64;
+        let arg = self.stack.get(self.func_index + 1).unwrap_or(&Value::Nil);
+        if matches!(arg, Value::String(s) if s.as_bytes() == b"#") {
+            self.stack.push(Value::Integer(top - 1));
+            return Ok(1);
+        }
+
+        let Some(n) = arith::to_int(arg) else {
+            bail!(
+                "bad argument #1 to 'select' (number expected, got {})",
+                if top == 0 {
+                    "no value"
+                } else {
+                    arg.type_name()
+                }
+            );
+        };
+        let n = if n < 0 { top + n } else { n.min(top) };
+        if n < 1 {
+            bail!("bad argument #1 to 'select' (index out of range)");
+        }
+        // 所需的参数已经位于栈顶
+        Ok((top - n) as i32)
+    }
+}
+
+/// Convert the limit of an integer loop to an integer, clipping floats out of range.
+/// Return `None` if the loop must not run because of the clipping.
+fn for_limit(limit: &Value, step: i64) -> anyhow::Result<Option<i64>> {
+    if let &Value::Integer(limit) = limit {
+        return Ok(Some(limit));
+    }
+
+    let Some(limit) = arith::to_float(limit) else {
+        bail!("'for' limit must be a number");
+    };
+    let limit = if step < 0 {
+        limit.ceil()
+    } else {
+        limit.floor()
+    };
+    if (-(2f64.powi(63))..2f64.powi(63)).contains(&limit) {
+        Ok(Some(limit as i64))
+    } else if limit > 0.0 {
+        // 超出整数范围的正数，初始值必然小于它
+        Ok((step > 0).then_some(i64::MAX))
+    } else {
+        Ok((step < 0).then_some(i64::MIN))
     }
 }